@@ -0,0 +1,212 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::spotify::{AudioSettings, OAuthSettings};
+
+// Bumped whenever a setting changes meaning, so older files can be migrated on load.
+const CONFIG_VERSION: u32 = 1;
+
+
+// Settings the user can change, kept in config.ron in the config dir.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub version: u32,
+
+    // Track info, covers, audio files, history and the saved session all go in here.
+    pub cache_path: PathBuf,
+
+    pub audio: AudioSettings,
+    pub oauth: OAuthSettings,
+    pub ui: UiSettings
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            version: CONFIG_VERSION,
+
+            cache_path: dirs::cache_dir().unwrap_or_default().join("espot-rs"),
+
+            audio: AudioSettings::default(),
+            oauth: OAuthSettings::default(),
+            ui: UiSettings::default()
+        }
+    }
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        Some(dirs::config_dir()?.join("espot-rs").join("config.ron"))
+    }
+
+    // Falls back to the defaults if there's no config yet, or if it can't be read.
+    // Without a config, whatever an older version left in eframe's storage is moved into a new one.
+    pub fn load(app_storage: Option<String>) -> Config {
+        let data = match Config::path().and_then(| path | std::fs::read_to_string(path).ok()) {
+            Some(data) => data,
+            None => return Config::migrate(app_storage.as_deref())
+        };
+
+        Config::parse(&data).unwrap_or_else(| e | {
+            println!("Error loading config, using the defaults: {}", e);
+            Config::default()
+        })
+    }
+
+    fn parse(data: &str) -> Result<Config, ron::Error> {
+        let mut config: Config = ron::from_str(data)?;
+
+        if config.version > CONFIG_VERSION {
+            println!("The config was written by a newer version of espot-rs, some settings may be ignored.");
+        }
+
+        // Nothing to migrate yet, older versions only differ in what was missing.
+        config.version = CONFIG_VERSION;
+        Ok(config)
+    }
+
+    fn migrate(app_storage: Option<&str>) -> Config {
+        let legacy = match app_storage.and_then(LegacySettings::parse) {
+            Some(legacy) => legacy,
+            None => return Config::default()
+        };
+
+        let config = legacy.into_config();
+
+        // The UI stops saving these, so this is the only chance to keep them.
+        if let Err(e) = config.save() {
+            println!("Error saving the migrated config: {}", e);
+        }
+
+        config
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let path = Config::path().ok_or_else(|| std::io::Error::other("no config directory"))?;
+        let data = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()).map_err(std::io::Error::other)?;
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        std::fs::write(path, data)
+    }
+}
+
+// Settings that were saved along with the UI's state, before there was a config file.
+#[derive(Deserialize)]
+struct LegacySettings {
+    cache_path: PathBuf,
+    #[serde(default)]
+    audio: AudioSettings
+}
+
+impl LegacySettings {
+    // Takes the app as eframe stored it, the settings were in its persistent data.
+    fn parse(app_storage: &str) -> Option<LegacySettings> {
+        #[derive(Deserialize)]
+        struct StoredApp {
+            p: LegacySettings
+        }
+
+        ron::from_str::<StoredApp>(app_storage).ok().map(| app | app.p)
+    }
+
+    fn into_config(self) -> Config {
+        Config {
+            cache_path: self.cache_path,
+            audio: self.audio,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UiSettings {
+    pub theme: Theme,
+    // Multiplies the scale the system asks for.
+    pub scale: f32
+}
+
+impl Default for UiSettings {
+    fn default() -> UiSettings {
+        UiSettings {
+            theme: Theme::System,
+            scale: 1.0
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Theme {
+    System,
+    Dark,
+    Light
+}
+
+impl Theme {
+    pub const ALL: [Theme; 3] = [Theme::System, Theme::Dark, Theme::Light];
+}
+
+impl std::fmt::Display for Theme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Theme::System => write!(f, "System"),
+            Theme::Dark => write!(f, "Dark"),
+            Theme::Light => write!(f, "Light")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let config = Config {
+            cache_path: PathBuf::from("/tmp/espot-rs"),
+            audio: AudioSettings { backend: Some(String::from("pipe")), ..Default::default() },
+            oauth: OAuthSettings { client_id: Some(String::from("id")), ..Default::default() },
+            ui: UiSettings { theme: Theme::Light, scale: 1.5 },
+            ..Default::default()
+        };
+
+        let data = ron::ser::to_string_pretty(&config, ron::ser::PrettyConfig::default()).unwrap();
+        assert_eq!(Config::parse(&data).unwrap(), config);
+    }
+
+    #[test]
+    fn missing_settings_get_their_defaults() {
+        let config = Config::parse("(version: 1, cache_path: \"/tmp/espot-rs\")").unwrap();
+
+        assert_eq!(config.cache_path, PathBuf::from("/tmp/espot-rs"));
+        assert_eq!(config.audio, AudioSettings::default());
+        assert_eq!(config.ui, UiSettings::default());
+    }
+
+    #[test]
+    fn settings_from_before_the_config_are_migrated() {
+        let stored = "(p:(cache_path:\"/tmp/old-cache\",login_username:\"user\",login_remember:true,volume:1000,audio:(backend:Some(\"pipe\"))))";
+        let config = LegacySettings::parse(stored).unwrap().into_config();
+
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.cache_path, PathBuf::from("/tmp/old-cache"));
+        assert_eq!(config.audio.backend.as_deref(), Some("pipe"));
+        assert_eq!(config.ui, UiSettings::default());
+
+        // What's stored now has nothing left to migrate.
+        assert!(LegacySettings::parse("(p:(login_username:\"user\",login_remember:true,volume:1000))").is_none());
+    }
+
+    #[test]
+    fn newer_configs_are_still_loaded() {
+        let config = Config::parse("(version: 100, ui: (theme: Dark))").unwrap();
+
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.ui.theme, Theme::Dark);
+    }
+}
@@ -1,10 +1,15 @@
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+
 use tokio::runtime::Runtime;
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, watch};
 
 use zbus::fdo::Result;
-use zbus::{Connection, dbus_interface};
+use zbus::zvariant::{ObjectPath, OwnedObjectPath, Value};
+use zbus::{Connection, SignalContext, dbus_interface};
 
-use crate::spotify::{PlayerControl, PlayerStateUpdate, TrackInfo};
+use crate::ui::AppCommand;
+use crate::spotify::{PlayableUri, PlaybackPosition, PlayerControl, PlayerStateUpdate, QueueEntry, RepaintCallback, RepeatMode, TrackInfo};
 
 #[derive(Clone)]
 enum PlaybackStatus {
@@ -13,27 +18,69 @@ enum PlaybackStatus {
     Stopped
 }
 
-impl ToString for PlaybackStatus {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for PlaybackStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            PlaybackStatus::Playing => "Playing".to_string(),
-            PlaybackStatus::Paused => "Paused".to_string(),
-            PlaybackStatus::Stopped => "Stopped".to_string()
+            PlaybackStatus::Playing => write!(f, "Playing"),
+            PlaybackStatus::Paused => write!(f, "Paused"),
+            PlaybackStatus::Stopped => write!(f, "Stopped")
         }
     }
 }
 
-struct Mpris;
+// MPRIS track ids are object paths, and have to be unique within the track list.
+// The same track can be queued more than once, so they're built out of the queue entry's ID.
+fn track_object_path(entry_id: u64) -> String {
+    format!("/org/espot/track/{}", entry_id)
+}
+
+fn playlist_object_path(id: &str) -> String {
+    let id = id.rsplit(':').next().unwrap_or_default();
+    format!("/org/espot/playlist/{}", id)
+}
+
+const NO_TRACK_PATH: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+fn track_metadata(track: Option<(u64, &TrackInfo)>, cache_dir: &Path) -> HashMap<String, Value<'static>> {
+    let mut metadata = HashMap::new();
+
+    if let Some((entry_id, track)) = track {
+        let track_id = ObjectPath::try_from(track_object_path(entry_id)).unwrap_or_else(|_| ObjectPath::from_static_str_unchecked(NO_TRACK_PATH));
+        let art_url = format!("file://{}", cache_dir.join(format!("cover-{}", track.album_id)).display());
+
+        metadata.insert(String::from("mpris:trackid"), Value::from(track_id));
+        metadata.insert(String::from("mpris:length"), Value::from(track.duration_ms as i64 * 1000));
+        metadata.insert(String::from("mpris:artUrl"), Value::from(art_url));
+
+        metadata.insert(String::from("xesam:title"), Value::from(track.name.clone()));
+        metadata.insert(String::from("xesam:artist"), Value::from(track.artists.clone()));
+        metadata.insert(String::from("xesam:album"), Value::from(track.album_name.clone()));
+    }
+    else {
+        metadata.insert(String::from("mpris:trackid"), Value::from(ObjectPath::from_static_str_unchecked(NO_TRACK_PATH)));
+    }
+
+    metadata
+}
+
+struct Mpris {
+    app_tx: mpsc::UnboundedSender<AppCommand>,
+    repaint: RepaintCallback
+}
 
 #[dbus_interface(name = "org.mpris.MediaPlayer2")]
 impl Mpris {
+    // eframe doesn't provide a way to focus the window, so CanRaise stays false.
     async fn raise(&self) {}
 
-    async fn quit(&self) {}
+    async fn quit(&self) {
+        self.app_tx.send(AppCommand::Quit).unwrap();
+        (self.repaint)();
+    }
 
     #[dbus_interface(property)]
     async fn can_quit(&self) -> bool {
-        false
+        true
     }
 
     #[dbus_interface(property)]
@@ -53,22 +100,46 @@ impl Mpris {
 
     #[dbus_interface(property)]
     async fn has_track_list(&self) -> bool {
-        false
+        true
     }
 
     #[dbus_interface(property)]
     async fn identity(&self) -> &str {
         "espot-rs"
     }
+
+    #[dbus_interface(property)]
+    async fn supported_uri_schemes(&self) -> Vec<String> {
+        vec![String::from("spotify"), String::from("https")]
+    }
+
+    #[dbus_interface(property)]
+    async fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
 }
 
 struct MprisPlayer {
     pub track: Option<TrackInfo>,
+    // ID of the queue entry being played.
+    pub entry_id: Option<u64>,
+    pub cache_dir: PathBuf,
     pub status: PlaybackStatus,
 
+    pub position: Option<PlaybackPosition>,
+    pub volume: u16,
+    pub shuffle: bool,
+    pub repeat: RepeatMode,
+
     control_tx: mpsc::UnboundedSender<PlayerControl>
 }
 
+impl MprisPlayer {
+    fn current(&self) -> Option<(u64, &TrackInfo)> {
+        self.entry_id.zip(self.track.as_ref())
+    }
+}
+
 #[dbus_interface(name = "org.mpris.MediaPlayer2.Player")]
 impl MprisPlayer {
     async fn next(&self) {
@@ -95,6 +166,62 @@ impl MprisPlayer {
         self.control_tx.send(PlayerControl::Play).unwrap();
     }
 
+    async fn seek(&self, offset: i64) {
+        self.control_tx.send(PlayerControl::SeekRelative(offset / 1000)).unwrap();
+    }
+
+    async fn set_position(&self, track_id: ObjectPath<'_>, position: i64) {
+        if let Some((entry_id, track)) = self.current() {
+            let valid_position = position >= 0 && (position / 1000) as u128 <= track.duration_ms;
+
+            // Per the spec, calls for a track that isn't the current one are ignored.
+            if valid_position && track_id.as_str() == track_object_path(entry_id) {
+                self.control_tx.send(PlayerControl::Seek((position / 1000) as u32)).unwrap();
+            }
+        }
+    }
+
+    async fn open_uri(&self, uri: String) -> Result<()> {
+        let uri = PlayableUri::parse(&uri).ok_or_else(|| zbus::fdo::Error::InvalidArgs(format!("Unsupported URI: {}", uri)))?;
+
+        self.control_tx.send(PlayerControl::PlayUri(uri)).unwrap();
+        Ok(())
+    }
+
+    #[dbus_interface(signal)]
+    async fn seeked(ctxt: &SignalContext<'_>, position: i64) -> zbus::Result<()>;
+
+    #[dbus_interface(property)]
+    async fn position(&self) -> i64 {
+        match (self.track.as_ref(), self.position.as_ref()) {
+            (Some(track), Some(position)) if track.id == position.track_id => {
+                position.current_position_ms() as i64 * 1000
+            }
+            _ => 0
+        }
+    }
+
+    #[dbus_interface(property)]
+    async fn volume(&self) -> f64 {
+        self.volume as f64 / u16::MAX as f64
+    }
+
+    #[dbus_interface(property)]
+    async fn set_volume(&mut self, value: f64) {
+        self.volume = (value.clamp(0.0, 1.0) * u16::MAX as f64) as u16;
+        self.control_tx.send(PlayerControl::SetVolume(self.volume)).unwrap();
+    }
+
+    #[dbus_interface(property)]
+    async fn can_seek(&self) -> bool {
+        self.track.is_some()
+    }
+
+    #[dbus_interface(property)]
+    async fn metadata(&self) -> HashMap<String, Value<'static>> {
+        track_metadata(self.current(), &self.cache_dir)
+    }
+
     #[dbus_interface(property)]
     async fn playback_status(&self) -> String {
         self.status.to_string()
@@ -102,12 +229,35 @@ impl MprisPlayer {
 
     #[dbus_interface(property)]
     async fn loop_status(&self) -> &str {
-        "Playlist"
+        match self.repeat {
+            RepeatMode::None => "None",
+            RepeatMode::Track => "Track",
+            RepeatMode::Playlist => "Playlist"
+        }
+    }
+
+    #[dbus_interface(property)]
+    async fn set_loop_status(&mut self, value: String) -> Result<()> {
+        self.repeat = match value.as_str() {
+            "None" => RepeatMode::None,
+            "Track" => RepeatMode::Track,
+            "Playlist" => RepeatMode::Playlist,
+            _ => return Err(zbus::fdo::Error::InvalidArgs(format!("Unknown loop status: {}", value)))
+        };
+
+        self.control_tx.send(PlayerControl::SetRepeat(self.repeat)).unwrap();
+        Ok(())
     }
 
     #[dbus_interface(property)]
     async fn shuffle(&self) -> bool {
-        true
+        self.shuffle
+    }
+
+    #[dbus_interface(property)]
+    async fn set_shuffle(&mut self, value: bool) {
+        self.shuffle = value;
+        self.control_tx.send(PlayerControl::SetShuffle(value)).unwrap();
     }
 
     #[dbus_interface(property)]
@@ -136,30 +286,209 @@ impl MprisPlayer {
     }
 }
 
+struct MprisTrackList {
+    pub tracks: Vec<QueueEntry>,
+    pub current_entry: Option<u64>,
+    pub cache_dir: PathBuf,
+
+    control_tx: mpsc::UnboundedSender<PlayerControl>
+}
+
+impl MprisTrackList {
+    fn track_idx(&self, track_id: &ObjectPath<'_>) -> Option<usize> {
+        self.tracks.iter().position(| entry | track_object_path(entry.id) == track_id.as_str())
+    }
+
+    fn track_paths(&self) -> Vec<OwnedObjectPath> {
+        self.tracks
+            .iter()
+            .filter_map(| entry | OwnedObjectPath::try_from(track_object_path(entry.id)).ok())
+            .collect()
+    }
+
+    fn current_track_path(&self) -> OwnedObjectPath {
+        let path = self.current_entry.map(track_object_path).unwrap_or_else(|| NO_TRACK_PATH.to_string());
+        OwnedObjectPath::try_from(path).unwrap_or_else(|_| ObjectPath::from_static_str_unchecked(NO_TRACK_PATH).into())
+    }
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2.TrackList")]
+impl MprisTrackList {
+    async fn get_tracks_metadata(&self, track_ids: Vec<ObjectPath<'_>>) -> Vec<HashMap<String, Value<'static>>> {
+        track_ids
+            .iter()
+            .filter_map(| id | self.track_idx(id))
+            .map(| idx | track_metadata(Some((self.tracks[idx].id, &self.tracks[idx].track)), &self.cache_dir))
+            .collect()
+    }
+
+    async fn add_track(&self, uri: String, after_track: ObjectPath<'_>, set_as_current: bool) {
+        let idx = {
+            if after_track.as_str() == NO_TRACK_PATH {
+                Some(0)
+            }
+            else {
+                self.track_idx(&after_track).map(| idx | idx + 1)
+            }
+        };
+
+        if let Some(idx) = idx {
+            self.control_tx.send(PlayerControl::AddTrack(uri, idx, set_as_current)).unwrap();
+        }
+    }
+
+    async fn remove_track(&self, track_id: ObjectPath<'_>) {
+        if let Some(idx) = self.track_idx(&track_id) {
+            self.control_tx.send(PlayerControl::RemoveTrack(idx)).unwrap();
+        }
+    }
+
+    async fn go_to(&self, track_id: ObjectPath<'_>) {
+        if let Some(idx) = self.track_idx(&track_id) {
+            self.control_tx.send(PlayerControl::GoToTrack(idx)).unwrap();
+        }
+    }
+
+    #[dbus_interface(signal)]
+    async fn track_list_replaced(ctxt: &SignalContext<'_>, tracks: Vec<OwnedObjectPath>, current_track: OwnedObjectPath) -> zbus::Result<()>;
+
+    #[dbus_interface(property)]
+    async fn tracks(&self) -> Vec<OwnedObjectPath> {
+        self.track_paths()
+    }
+
+    #[dbus_interface(property)]
+    async fn can_edit_tracks(&self) -> bool {
+        true
+    }
+}
+
+// Playlist object path, name and icon, as used by the Playlists interface.
+type MprisPlaylist = (OwnedObjectPath, String, String);
+
+struct MprisPlaylists {
+    // ID and name of each playlist.
+    pub playlists: Vec<(String, String)>,
+    pub active_playlist: Option<String>,
+    pub cache_dir: PathBuf,
+
+    control_tx: mpsc::UnboundedSender<PlayerControl>
+}
+
+impl MprisPlaylists {
+    fn make_playlist(&self, id: &str, name: &str) -> Option<MprisPlaylist> {
+        let path = OwnedObjectPath::try_from(playlist_object_path(id)).ok()?;
+        let icon = self.cache_dir.join(format!("cover-{}", id));
+        let icon = if icon.exists() { format!("file://{}", icon.display()) } else { String::new() };
+
+        Some((path, name.to_string(), icon))
+    }
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2.Playlists")]
+impl MprisPlaylists {
+    async fn activate_playlist(&self, playlist_id: ObjectPath<'_>) {
+        let playlist = self.playlists.iter().find(| (id, _) | playlist_object_path(id) == playlist_id.as_str());
+
+        if let Some((id, _)) = playlist {
+            self.control_tx.send(PlayerControl::StartPlaylistById(id.clone())).unwrap();
+        }
+    }
+
+    async fn get_playlists(&self, index: u32, max_count: u32, order: String, reverse_order: bool) -> Vec<MprisPlaylist> {
+        let mut playlists: Vec<&(String, String)> = self.playlists.iter().collect();
+
+        // UserDefined is the order Spotify returns them in, which is what we store.
+        if order == "Alphabetical" {
+            playlists.sort_by_key(| (_, name) | name.to_lowercase());
+        }
+
+        if reverse_order {
+            playlists.reverse();
+        }
+
+        playlists
+            .into_iter()
+            .skip(index as usize)
+            .take(max_count as usize)
+            .filter_map(| (id, name) | self.make_playlist(id, name))
+            .collect()
+    }
+
+    #[dbus_interface(property)]
+    async fn playlist_count(&self) -> u32 {
+        self.playlists.len() as u32
+    }
+
+    #[dbus_interface(property)]
+    async fn orderings(&self) -> Vec<String> {
+        vec![String::from("Alphabetical"), String::from("UserDefined")]
+    }
+
+    #[dbus_interface(property)]
+    async fn active_playlist(&self) -> (bool, MprisPlaylist) {
+        let active = self.active_playlist
+            .as_ref()
+            .and_then(| active | self.playlists.iter().find(| (id, _) | id == active))
+            .and_then(| (id, name) | self.make_playlist(id, name))
+        ;
+
+        match active {
+            Some(playlist) => (true, playlist),
+            None => (false, (ObjectPath::from_static_str_unchecked("/").into(), String::new(), String::new()))
+        }
+    }
+}
+
 
-pub fn start_dbus_server(state_rx: broadcast::Receiver<PlayerStateUpdate>, control_tx: mpsc::UnboundedSender<PlayerControl>) {
+pub fn start_dbus_server(state_rx: broadcast::Receiver<PlayerStateUpdate>, position_rx: watch::Receiver<Option<PlaybackPosition>>, control_tx: mpsc::UnboundedSender<PlayerControl>, app_tx: mpsc::UnboundedSender<AppCommand>, repaint: RepaintCallback, cache_dir: PathBuf) {
     std::thread::spawn(move || {
         let rt = Runtime::new().unwrap();
 
-        if let Err(e) = rt.block_on(dbus_loop(state_rx, control_tx)) {
+        let result = rt.block_on(async {
+            let connection = Connection::session().await?;
+            dbus_loop(&connection, state_rx, position_rx, control_tx, app_tx, repaint, cache_dir).await
+        });
+
+        if let Err(e) = result {
             println!("Error in dbus server: {}", e);
         }
     });
 }
 
-async fn dbus_loop(state_rx: broadcast::Receiver<PlayerStateUpdate>, control_tx: mpsc::UnboundedSender<PlayerControl>) -> Result<()> {
-    let connection = Connection::session().await?;
-    let mut state_rx = state_rx;
+async fn register_interfaces(connection: &Connection, control_tx: mpsc::UnboundedSender<PlayerControl>, app_tx: mpsc::UnboundedSender<AppCommand>, repaint: RepaintCallback, cache_dir: PathBuf) -> Result<()> {
+    let track_list = MprisTrackList {
+        tracks: Vec::new(),
+        current_entry: None,
+        cache_dir: cache_dir.clone(),
+
+        control_tx: control_tx.clone()
+    };
+
+    let playlists = MprisPlaylists {
+        playlists: Vec::new(),
+        active_playlist: None,
+        cache_dir: cache_dir.clone(),
+
+        control_tx: control_tx.clone()
+    };
 
     let handler = MprisPlayer {
         track: None,
+        entry_id: None,
+        cache_dir,
         status: PlaybackStatus::Stopped,
 
+        position: None,
+        volume: u16::MAX,
+        shuffle: false,
+        repeat: RepeatMode::default(),
+
         control_tx
     };
 
     connection.object_server()
-        .at("/org/mpris/MediaPlayer2", Mpris)
+        .at("/org/mpris/MediaPlayer2", Mpris { app_tx, repaint })
         .await?
     ;
 
@@ -168,44 +497,152 @@ async fn dbus_loop(state_rx: broadcast::Receiver<PlayerStateUpdate>, control_tx:
         .await?
     ;
 
+    connection.object_server()
+        .at("/org/mpris/MediaPlayer2", track_list)
+        .await?
+    ;
+
+    connection.object_server()
+        .at("/org/mpris/MediaPlayer2", playlists)
+        .await?
+    ;
+
     connection
         .request_name("org.mpris.MediaPlayer2.espot")
         .await?
     ;
 
+    Ok(())
+}
+
+async fn dbus_loop(connection: &Connection, state_rx: broadcast::Receiver<PlayerStateUpdate>, position_rx: watch::Receiver<Option<PlaybackPosition>>, control_tx: mpsc::UnboundedSender<PlayerControl>, app_tx: mpsc::UnboundedSender<AppCommand>, repaint: RepaintCallback, cache_dir: PathBuf) -> Result<()> {
+    let mut state_rx = state_rx;
+    let mut position_rx = position_rx;
+
+    register_interfaces(connection, control_tx, app_tx, repaint, cache_dir).await?;
+
     let iface_ref = connection.object_server().interface::<_, MprisPlayer>("/org/mpris/MediaPlayer2").await?;
+    let track_list_ref = connection.object_server().interface::<_, MprisTrackList>("/org/mpris/MediaPlayer2").await?;
+    let playlists_ref = connection.object_server().interface::<_, MprisPlaylists>("/org/mpris/MediaPlayer2").await?;
 
     loop {
-        if let Ok(status) = state_rx.recv().await {
-            match status {
-                PlayerStateUpdate::Paused => {
-                    let mut iface_mut = iface_ref.get_mut().await;
-
-                    iface_mut.status = PlaybackStatus::Paused;
-                    iface_mut.playback_status_changed(iface_ref.signal_context()).await?;
+        let status = tokio::select! {
+            status = state_rx.recv() => match status {
+                Ok(status) => status,
+                // Missing a few updates isn't the end of the world, the next ones will catch up.
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                // The worker is gone, so there's nothing left to serve.
+                Err(broadcast::error::RecvError::Closed) => return Ok(())
+            },
+            changed = position_rx.changed() => {
+                if changed.is_err() {
+                    return Ok(());
                 }
-                PlayerStateUpdate::Resumed => {
-                    let mut iface_mut = iface_ref.get_mut().await;
 
-                    iface_mut.status = PlaybackStatus::Playing;
-                    iface_mut.playback_status_changed(iface_ref.signal_context()).await?;
-                }
-                PlayerStateUpdate::Stopped => {
-                    let mut iface_mut = iface_ref.get_mut().await;
+                let position = position_rx.borrow_and_update().clone();
 
-                    iface_mut.track = None;
-                    iface_mut.status = PlaybackStatus::Stopped;
-                    iface_mut.can_play_changed(iface_ref.signal_context()).await?;
-                    iface_mut.playback_status_changed(iface_ref.signal_context()).await?;
-                }
-                PlayerStateUpdate::EndOfTrack(track) => {
-                    let mut iface_mut = iface_ref.get_mut().await;
+                // Position changes aren't announced through PropertiesChanged, per the spec.
+                iface_ref.get_mut().await.position = position;
+                continue;
+            }
+        };
+
+        match status {
+            PlayerStateUpdate::Paused => {
+                let mut iface_mut = iface_ref.get_mut().await;
+
+                iface_mut.status = PlaybackStatus::Paused;
+                iface_mut.playback_status_changed(iface_ref.signal_context()).await?;
+            }
+            PlayerStateUpdate::Resumed => {
+                let mut iface_mut = iface_ref.get_mut().await;
 
-                    iface_mut.track = Some(track);
+                iface_mut.status = PlaybackStatus::Playing;
+                iface_mut.playback_status_changed(iface_ref.signal_context()).await?;
+            }
+            PlayerStateUpdate::Stopped => {
+                track_list_ref.get_mut().await.current_entry = None;
+
+                let mut iface_mut = iface_ref.get_mut().await;
+
+                iface_mut.track = None;
+                iface_mut.entry_id = None;
+                iface_mut.status = PlaybackStatus::Stopped;
+                iface_mut.position = None;
+                iface_mut.metadata_changed(iface_ref.signal_context()).await?;
+                iface_mut.can_play_changed(iface_ref.signal_context()).await?;
+                iface_mut.can_seek_changed(iface_ref.signal_context()).await?;
+                iface_mut.playback_status_changed(iface_ref.signal_context()).await?;
+            }
+            PlayerStateUpdate::Seeked(position_ms) => {
+                MprisPlayer::seeked(iface_ref.signal_context(), position_ms as i64 * 1000).await?;
+            }
+            PlayerStateUpdate::Volume(volume) => {
+                let mut iface_mut = iface_ref.get_mut().await;
+
+                iface_mut.volume = volume;
+                iface_mut.volume_changed(iface_ref.signal_context()).await?;
+            }
+            // MPRIS has nothing to report it through.
+            PlayerStateUpdate::Bitrate(_) => {}
+            PlayerStateUpdate::Shuffle(shuffle) => {
+                let mut iface_mut = iface_ref.get_mut().await;
+
+                iface_mut.shuffle = shuffle;
+                iface_mut.shuffle_changed(iface_ref.signal_context()).await?;
+            }
+            PlayerStateUpdate::Repeat(repeat) => {
+                let mut iface_mut = iface_ref.get_mut().await;
+
+                iface_mut.repeat = repeat;
+                iface_mut.loop_status_changed(iface_ref.signal_context()).await?;
+            }
+            PlayerStateUpdate::Queue(entries, current) => {
+                let entry_id = entries.get(current).map(| entry | entry.id);
+
+                // Comes before the track starts, so its metadata already has the right ID.
+                iface_ref.get_mut().await.entry_id = entry_id;
+
+                let mut track_list_mut = track_list_ref.get_mut().await;
+
+                // Also sent whenever the current track changes, which isn't worth replacing the list over.
+                let replaced = track_list_mut.tracks.len() != entries.len()
+                    || track_list_mut.tracks.iter().zip(entries.iter()).any(| (old, new) | old.id != new.id)
+                ;
+
+                track_list_mut.tracks = entries;
+                track_list_mut.current_entry = entry_id;
+
+                if replaced {
+                    let paths = track_list_mut.track_paths();
+                    let current = track_list_mut.current_track_path();
+
+                    MprisTrackList::track_list_replaced(track_list_ref.signal_context(), paths, current).await?;
                 }
             }
-        }
+            PlayerStateUpdate::UserPlaylists(user_playlists) => {
+                let mut playlists_mut = playlists_ref.get_mut().await;
+
+                playlists_mut.playlists = user_playlists;
+                playlists_mut.playlist_count_changed(playlists_ref.signal_context()).await?;
+            }
+            PlayerStateUpdate::ActivePlaylist(active_playlist) => {
+                let mut playlists_mut = playlists_ref.get_mut().await;
 
-        std::thread::sleep(std::time::Duration::from_millis(10));
+                playlists_mut.active_playlist = active_playlist;
+                playlists_mut.active_playlist_changed(playlists_ref.signal_context()).await?;
+            }
+            PlayerStateUpdate::EndOfTrack(track) => {
+                let mut iface_mut = iface_ref.get_mut().await;
+
+                iface_mut.track = Some(track);
+                iface_mut.metadata_changed(iface_ref.signal_context()).await?;
+                iface_mut.can_play_changed(iface_ref.signal_context()).await?;
+                iface_mut.can_seek_changed(iface_ref.signal_context()).await?;
+            }
+        }
     }
 }
+
+#[cfg(test)]
+mod tests;
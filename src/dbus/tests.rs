@@ -0,0 +1,275 @@
+use std::sync::Arc;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+
+use zbus::ConnectionBuilder;
+use zbus::zvariant::Value;
+
+use super::*;
+use crate::spotify::fake::test_track;
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.espot";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+
+// A private session bus, so tests don't depend on (or mess with) the user's.
+struct TestBus {
+    daemon: Child,
+    address: String
+}
+
+impl TestBus {
+    // Without dbus-daemon these tests can't check anything, so they fail rather than pass without running.
+    // Build without the mpris feature (or skip `dbus::`) where it isn't installed.
+    fn start() -> TestBus {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("the MPRIS tests need dbus-daemon")
+        ;
+
+        let mut address = String::new();
+        let stdout = daemon.stdout.take().unwrap();
+        BufReader::new(stdout).read_line(&mut address).unwrap();
+
+        TestBus { daemon, address: address.trim().to_string() }
+    }
+
+    async fn connect(&self) -> Connection {
+        ConnectionBuilder::address(self.address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap()
+    }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+struct TestServer {
+    _bus: TestBus,
+    server: Connection,
+    client: Connection,
+    control_rx: mpsc::UnboundedReceiver<PlayerControl>,
+    app_rx: mpsc::UnboundedReceiver<AppCommand>
+}
+
+impl TestServer {
+    async fn start() -> TestServer {
+        let bus = TestBus::start();
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        let (app_tx, app_rx) = mpsc::unbounded_channel();
+
+        let server = bus.connect().await;
+        let client = bus.connect().await;
+
+        register_interfaces(&server, control_tx, app_tx, Arc::new(|| {}), std::env::temp_dir()).await.unwrap();
+
+        TestServer { _bus: bus, server, client, control_rx, app_rx }
+    }
+
+    async fn call<B>(&self, interface: &str, method: &str, body: &B)
+    where
+        B: serde::Serialize + zbus::zvariant::DynamicType
+    {
+        self.try_call(interface, method, body).await.unwrap();
+    }
+
+    async fn try_call<B>(&self, interface: &str, method: &str, body: &B) -> zbus::Result<()>
+    where
+        B: serde::Serialize + zbus::zvariant::DynamicType
+    {
+        self.client
+            .call_method(Some(BUS_NAME), OBJECT_PATH, Some(interface), method, body)
+            .await
+            .map(| _ | ())
+    }
+
+    async fn set_property(&self, interface: &str, property: &str, value: Value<'_>) -> zbus::Result<()> {
+        self.try_call("org.freedesktop.DBus.Properties", "Set", &(interface, property, value)).await
+    }
+
+    // Entries get their position in the queue as their ID.
+    async fn set_queue(&self, tracks: Vec<TrackInfo>) {
+        let track_list_ref = self.server.object_server().interface::<_, MprisTrackList>(OBJECT_PATH).await.unwrap();
+        let entries = tracks.into_iter().enumerate().map(| (id, track) | QueueEntry { id: id as u64, track }).collect();
+
+        track_list_ref.get_mut().await.tracks = entries;
+    }
+
+    fn next_control(&mut self) -> PlayerControl {
+        self.control_rx.try_recv().expect("no PlayerControl message was sent")
+    }
+}
+
+#[tokio::test]
+async fn player_methods_send_controls() {
+    let mut server = TestServer::start().await;
+    let player = "org.mpris.MediaPlayer2.Player";
+
+    server.call(player, "Play", &()).await;
+    assert!(matches!(server.next_control(), PlayerControl::Play));
+
+    server.call(player, "Pause", &()).await;
+    assert!(matches!(server.next_control(), PlayerControl::Pause));
+
+    server.call(player, "PlayPause", &()).await;
+    assert!(matches!(server.next_control(), PlayerControl::PlayPause));
+
+    server.call(player, "Stop", &()).await;
+    assert!(matches!(server.next_control(), PlayerControl::Stop));
+
+    server.call(player, "Next", &()).await;
+    assert!(matches!(server.next_control(), PlayerControl::NextTrack));
+
+    server.call(player, "Previous", &()).await;
+    assert!(matches!(server.next_control(), PlayerControl::PreviousTrack));
+
+    // MPRIS offsets are in microseconds.
+    server.call(player, "Seek", &(-5_000_000i64)).await;
+    assert!(matches!(server.next_control(), PlayerControl::SeekRelative(-5000)));
+}
+
+#[tokio::test]
+async fn set_position_only_applies_to_current_track() {
+    let mut server = TestServer::start().await;
+    let player = "org.mpris.MediaPlayer2.Player";
+
+    let iface_ref = server.server.object_server().interface::<_, MprisPlayer>(OBJECT_PATH).await.unwrap();
+    iface_ref.get_mut().await.track = Some(test_track(0));
+    iface_ref.get_mut().await.entry_id = Some(0);
+
+    let current = ObjectPath::try_from("/org/espot/track/0").unwrap();
+    let other = ObjectPath::try_from("/org/espot/track/1").unwrap();
+
+    server.call(player, "SetPosition", &(other, 1_000_000i64)).await;
+    assert!(server.control_rx.try_recv().is_err());
+
+    server.call(player, "SetPosition", &(current.clone(), 500_000_000i64)).await;
+    assert!(server.control_rx.try_recv().is_err());
+
+    server.call(player, "SetPosition", &(current, 1_000_000i64)).await;
+    assert!(matches!(server.next_control(), PlayerControl::Seek(1000)));
+}
+
+#[tokio::test]
+async fn player_properties_send_controls() {
+    let mut server = TestServer::start().await;
+    let player = "org.mpris.MediaPlayer2.Player";
+
+    server.set_property(player, "Volume", Value::from(0.0f64)).await.unwrap();
+    assert!(matches!(server.next_control(), PlayerControl::SetVolume(0)));
+
+    server.set_property(player, "Volume", Value::from(2.0f64)).await.unwrap();
+    assert!(matches!(server.next_control(), PlayerControl::SetVolume(u16::MAX)));
+
+    server.set_property(player, "Shuffle", Value::from(true)).await.unwrap();
+    assert!(matches!(server.next_control(), PlayerControl::SetShuffle(true)));
+
+    server.set_property(player, "LoopStatus", Value::from("Track")).await.unwrap();
+    assert!(matches!(server.next_control(), PlayerControl::SetRepeat(RepeatMode::Track)));
+
+    assert!(server.set_property(player, "LoopStatus", Value::from("Sometimes")).await.is_err());
+    assert!(server.control_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn track_list_methods_send_controls() {
+    let mut server = TestServer::start().await;
+    let track_list = "org.mpris.MediaPlayer2.TrackList";
+
+    server.set_queue(vec![test_track(0), test_track(1)]).await;
+
+    let first = ObjectPath::try_from("/org/espot/track/0").unwrap();
+    let second = ObjectPath::try_from("/org/espot/track/1").unwrap();
+    let missing = ObjectPath::try_from("/org/espot/track/2").unwrap();
+
+    server.call(track_list, "GoTo", &second).await;
+    assert!(matches!(server.next_control(), PlayerControl::GoToTrack(1)));
+
+    server.call(track_list, "RemoveTrack", &first).await;
+    assert!(matches!(server.next_control(), PlayerControl::RemoveTrack(0)));
+
+    server.call(track_list, "GoTo", &missing).await;
+    assert!(server.control_rx.try_recv().is_err());
+
+    server.call(track_list, "AddTrack", &("spotify:track:new", first, true)).await;
+    match server.next_control() {
+        PlayerControl::AddTrack(uri, idx, set_as_current) => {
+            assert_eq!(uri, "spotify:track:new");
+            assert_eq!(idx, 1);
+            assert!(set_as_current);
+        }
+        control => panic!("unexpected control: {:?}", control)
+    }
+}
+
+#[tokio::test]
+async fn repeated_tracks_have_their_own_ids() {
+    let mut server = TestServer::start().await;
+    let track_list = "org.mpris.MediaPlayer2.TrackList";
+
+    server.set_queue(vec![test_track(0), test_track(0)]).await;
+
+    let track_list_ref = server.server.object_server().interface::<_, MprisTrackList>(OBJECT_PATH).await.unwrap();
+    let paths = track_list_ref.get().await.track_paths();
+    assert_eq!(paths.len(), 2);
+    assert_ne!(paths[0], paths[1]);
+
+    server.call(track_list, "GoTo", &paths[1]).await;
+    assert!(matches!(server.next_control(), PlayerControl::GoToTrack(1)));
+
+    server.call(track_list, "RemoveTrack", &paths[1]).await;
+    assert!(matches!(server.next_control(), PlayerControl::RemoveTrack(1)));
+}
+
+#[tokio::test]
+async fn activate_playlist_sends_control() {
+    let mut server = TestServer::start().await;
+    let playlists = "org.mpris.MediaPlayer2.Playlists";
+
+    let playlists_ref = server.server.object_server().interface::<_, MprisPlaylists>(OBJECT_PATH).await.unwrap();
+    playlists_ref.get_mut().await.playlists = vec![(String::from("spotify:playlist:abc"), String::from("Playlist"))];
+
+    server.call(playlists, "ActivatePlaylist", &ObjectPath::try_from("/org/espot/playlist/abc").unwrap()).await;
+
+    match server.next_control() {
+        PlayerControl::StartPlaylistById(id) => assert_eq!(id, "spotify:playlist:abc"),
+        control => panic!("unexpected control: {:?}", control)
+    }
+}
+
+#[tokio::test]
+async fn quit_sends_app_command() {
+    let mut server = TestServer::start().await;
+
+    server.call("org.mpris.MediaPlayer2", "Quit", &()).await;
+    assert!(matches!(server.app_rx.try_recv(), Ok(AppCommand::Quit)));
+}
+
+#[tokio::test]
+async fn open_uri_sends_control() {
+    let mut server = TestServer::start().await;
+    let player = "org.mpris.MediaPlayer2.Player";
+
+    server.call(player, "OpenUri", &"spotify:album:4aawyAB9vmqN3uQ7FjRGTy").await;
+    match server.next_control() {
+        PlayerControl::PlayUri(uri) => assert_eq!(uri, PlayableUri::Album(String::from("spotify:album:4aawyAB9vmqN3uQ7FjRGTy"))),
+        control => panic!("unexpected control: {:?}", control)
+    }
+
+    server.call(player, "OpenUri", &"https://open.spotify.com/intl-es/track/6rqhFgbbKwnb9MLmUQDhG6?si=abc").await;
+    match server.next_control() {
+        PlayerControl::PlayUri(uri) => assert_eq!(uri, PlayableUri::Track(String::from("spotify:track:6rqhFgbbKwnb9MLmUQDhG6"))),
+        control => panic!("unexpected control: {:?}", control)
+    }
+
+    assert!(server.try_call(player, "OpenUri", &"spotify:artist:0OdUWJ0sBjDrqHygGUXeCF").await.is_err());
+    assert!(server.try_call(player, "OpenUri", &"file:///tmp/track.mp3").await.is_err());
+    assert!(server.control_rx.try_recv().is_err());
+}
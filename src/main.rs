@@ -1,10 +1,10 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-#[cfg(target_os = "linux")]
-#[cfg(not(debug_assertions))]
+#[cfg(all(target_os = "linux", feature = "mpris"))]
 mod dbus;
 
 mod ui;
+mod config;
 mod spotify;
 
 fn main() {
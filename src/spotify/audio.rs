@@ -0,0 +1,323 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use librespot::playback::player;
+use librespot::playback::audio_backend::{self, SinkBuilder};
+use librespot::playback::config::{self, AudioFormat, PlayerConfig, Bitrate as PlayerBitrate};
+
+
+// How the player outputs audio. Everything left empty uses librespot's defaults.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AudioSettings {
+    // Name of one of librespot's audio backends.
+    pub backend: Option<String>,
+    // What it means depends on the backend, the pipe backend takes a file path for example.
+    pub device: Option<String>,
+    pub format: SampleFormat,
+    pub bitrate: Bitrate,
+
+    pub gapless: bool,
+    pub normalisation: NormalisationSettings
+}
+
+impl Default for AudioSettings {
+    fn default() -> AudioSettings {
+        AudioSettings {
+            backend: None,
+            device: None,
+            format: SampleFormat::default(),
+            bitrate: Bitrate::default(),
+
+            gapless: true,
+            normalisation: NormalisationSettings::default()
+        }
+    }
+}
+
+impl AudioSettings {
+    pub fn sink_builder(&self) -> Option<SinkBuilder> {
+        audio_backend::find(self.backend.clone())
+    }
+
+    // The bitrate is passed in, since data saver can override the one in here.
+    pub fn player_config(&self, bitrate: Bitrate) -> PlayerConfig {
+        let normalisation = &self.normalisation;
+
+        PlayerConfig {
+            bitrate: bitrate.into(),
+            gapless: self.gapless,
+
+            normalisation: normalisation.enabled,
+            normalisation_type: normalisation.kind.into(),
+            normalisation_method: normalisation.method.into(),
+            normalisation_pregain: normalisation.pregain_db,
+            normalisation_threshold: player::db_to_ratio(normalisation.threshold_dbfs),
+            normalisation_attack: Duration::from_millis(normalisation.attack_ms as u64),
+            normalisation_release: Duration::from_millis(normalisation.release_ms as u64),
+
+            ..Default::default()
+        }
+    }
+}
+
+// Volume normalisation, using the loudness info Spotify has for each track.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NormalisationSettings {
+    pub enabled: bool,
+    pub kind: NormalisationType,
+    pub method: NormalisationMethod,
+
+    pub pregain_db: f64,
+    // The rest are only used by the dynamic method's limiter.
+    pub threshold_dbfs: f64,
+    pub attack_ms: u32,
+    pub release_ms: u32
+}
+
+// Same as librespot's, except for the threshold, which it takes as a ratio.
+impl Default for NormalisationSettings {
+    fn default() -> NormalisationSettings {
+        NormalisationSettings {
+            enabled: false,
+            kind: NormalisationType::Auto,
+            method: NormalisationMethod::Dynamic,
+
+            pregain_db: 0.0,
+            threshold_dbfs: -2.0,
+            attack_ms: 5,
+            release_ms: 100
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum NormalisationType {
+    Album,
+    Track,
+    // Album while playing an album in order, track otherwise.
+    Auto
+}
+
+impl NormalisationType {
+    pub const ALL: [NormalisationType; 3] = [NormalisationType::Album, NormalisationType::Track, NormalisationType::Auto];
+}
+
+impl std::fmt::Display for NormalisationType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NormalisationType::Album => write!(f, "Album"),
+            NormalisationType::Track => write!(f, "Track"),
+            NormalisationType::Auto => write!(f, "Auto")
+        }
+    }
+}
+
+impl From<NormalisationType> for config::NormalisationType {
+    fn from(kind: NormalisationType) -> config::NormalisationType {
+        match kind {
+            NormalisationType::Album => config::NormalisationType::Album,
+            NormalisationType::Track => config::NormalisationType::Track,
+            NormalisationType::Auto => config::NormalisationType::Auto
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum NormalisationMethod {
+    Basic,
+    // Basic, plus a limiter to avoid clipping.
+    Dynamic
+}
+
+impl NormalisationMethod {
+    pub const ALL: [NormalisationMethod; 2] = [NormalisationMethod::Basic, NormalisationMethod::Dynamic];
+}
+
+impl std::fmt::Display for NormalisationMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NormalisationMethod::Basic => write!(f, "Basic"),
+            NormalisationMethod::Dynamic => write!(f, "Dynamic")
+        }
+    }
+}
+
+impl From<NormalisationMethod> for config::NormalisationMethod {
+    fn from(method: NormalisationMethod) -> config::NormalisationMethod {
+        match method {
+            NormalisationMethod::Basic => config::NormalisationMethod::Basic,
+            NormalisationMethod::Dynamic => config::NormalisationMethod::Dynamic
+        }
+    }
+}
+
+// Quality of the audio files requested from Spotify.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Bitrate {
+    Kbps96,
+    #[default]
+    Kbps160,
+    Kbps320
+}
+
+impl Bitrate {
+    pub const ALL: [Bitrate; 3] = [Bitrate::Kbps96, Bitrate::Kbps160, Bitrate::Kbps320];
+}
+
+impl std::fmt::Display for Bitrate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Bitrate::Kbps96 => write!(f, "96 kbps"),
+            Bitrate::Kbps160 => write!(f, "160 kbps"),
+            Bitrate::Kbps320 => write!(f, "320 kbps")
+        }
+    }
+}
+
+impl From<Bitrate> for PlayerBitrate {
+    fn from(bitrate: Bitrate) -> PlayerBitrate {
+        match bitrate {
+            Bitrate::Kbps96 => PlayerBitrate::Bitrate96,
+            Bitrate::Kbps160 => PlayerBitrate::Bitrate160,
+            Bitrate::Kbps320 => PlayerBitrate::Bitrate320
+        }
+    }
+}
+
+// Mirrors librespot's AudioFormat, which can't be serialized.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum SampleFormat {
+    F64,
+    F32,
+    S32,
+    S24,
+    S24_3,
+    #[default]
+    S16
+}
+
+impl SampleFormat {
+    pub const ALL: [SampleFormat; 6] = [
+        SampleFormat::F64,
+        SampleFormat::F32,
+        SampleFormat::S32,
+        SampleFormat::S24,
+        SampleFormat::S24_3,
+        SampleFormat::S16
+    ];
+}
+
+impl std::fmt::Display for SampleFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SampleFormat::F64 => write!(f, "F64"),
+            SampleFormat::F32 => write!(f, "F32"),
+            SampleFormat::S32 => write!(f, "S32"),
+            SampleFormat::S24 => write!(f, "S24"),
+            SampleFormat::S24_3 => write!(f, "S24_3"),
+            SampleFormat::S16 => write!(f, "S16")
+        }
+    }
+}
+
+impl From<SampleFormat> for AudioFormat {
+    fn from(format: SampleFormat) -> AudioFormat {
+        match format {
+            SampleFormat::F64 => AudioFormat::F64,
+            SampleFormat::F32 => AudioFormat::F32,
+            SampleFormat::S32 => AudioFormat::S32,
+            SampleFormat::S24 => AudioFormat::S24,
+            SampleFormat::S24_3 => AudioFormat::S24_3,
+            SampleFormat::S16 => AudioFormat::S16
+        }
+    }
+}
+
+// Names of the backends librespot was built with, the first one's the default.
+pub fn backends() -> Vec<&'static str> {
+    audio_backend::BACKENDS.iter().map(| (name, _) | *name).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipe_and_subprocess_are_always_available() {
+        let backends = backends();
+
+        assert!(backends.contains(&"pipe"));
+        assert!(backends.contains(&"subprocess"));
+    }
+
+    #[test]
+    fn unknown_backends_have_no_sink() {
+        let mut settings = AudioSettings::default();
+        assert!(settings.sink_builder().is_some());
+
+        settings.backend = Some(String::from("pipe"));
+        assert!(settings.sink_builder().is_some());
+
+        settings.backend = Some(String::from("gramophone"));
+        assert!(settings.sink_builder().is_none());
+    }
+
+    #[test]
+    fn player_config_follows_the_settings() {
+        let settings = AudioSettings {
+            gapless: false,
+            normalisation: NormalisationSettings {
+                enabled: true,
+                kind: NormalisationType::Album,
+                method: NormalisationMethod::Basic,
+                pregain_db: 3.0,
+                threshold_dbfs: 0.0,
+                release_ms: 250,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let config = settings.player_config(Bitrate::Kbps320);
+
+        assert_eq!(config.bitrate, PlayerBitrate::Bitrate320);
+        assert!(!config.gapless);
+        assert!(config.normalisation);
+        assert_eq!(config.normalisation_type, config::NormalisationType::Album);
+        assert_eq!(config.normalisation_method, config::NormalisationMethod::Basic);
+        assert_eq!(config.normalisation_pregain, 3.0);
+        assert_eq!(config.normalisation_threshold, 1.0);
+        assert_eq!(config.normalisation_attack, Duration::from_millis(5));
+        assert_eq!(config.normalisation_release, Duration::from_millis(250));
+    }
+
+    #[test]
+    fn old_settings_get_the_new_defaults() {
+        let settings: AudioSettings = ron::from_str("(backend: Some(\"pipe\"), device: None, format: S16)").unwrap();
+
+        assert_eq!(settings.backend.as_deref(), Some("pipe"));
+        assert!(settings.gapless);
+        assert_eq!(settings.normalisation, NormalisationSettings::default());
+    }
+
+    #[test]
+    fn defaults_match_librespot() {
+        assert_eq!(AudioFormat::from(SampleFormat::default()), AudioFormat::default());
+        assert_eq!(PlayerBitrate::from(Bitrate::default()), PlayerBitrate::default());
+
+        let config = AudioSettings::default().player_config(Bitrate::default());
+        let default_config = PlayerConfig::default();
+
+        assert_eq!(config.gapless, default_config.gapless);
+        assert_eq!(config.normalisation, default_config.normalisation);
+        assert_eq!(config.normalisation_type, default_config.normalisation_type);
+        assert_eq!(config.normalisation_method, default_config.normalisation_method);
+        assert_eq!(config.normalisation_pregain, default_config.normalisation_pregain);
+        assert!((config.normalisation_threshold - default_config.normalisation_threshold).abs() < f64::EPSILON);
+        assert_eq!(config.normalisation_attack, default_config.normalisation_attack);
+        assert_eq!(config.normalisation_release, default_config.normalisation_release);
+    }
+}
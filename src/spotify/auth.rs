@@ -0,0 +1,361 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Response, Server};
+use url::{Host, Url};
+
+use rspotify::clients::OAuthClient;
+use rspotify::{ClientResult, Credentials, OAuth, Token};
+
+use super::{Result, TaskError, error::APILoginError};
+
+// Used when neither the settings nor the environment have one.
+pub const DEFAULT_REDIRECT_URI: &str = "http://127.0.0.1:8888/callback";
+// How long to wait for the user to go through Spotify's login page.
+pub const CALLBACK_TIMEOUT: Duration = Duration::from_secs(300);
+
+
+// The Web API client to log in with. Anything left empty is read from the environment (or .env) instead.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OAuthSettings {
+    pub client_id: Option<String>,
+    // Only needed by apps that aren't set up for PKCE.
+    pub client_secret: Option<String>,
+    // Has to point to this computer, the callback is served from here.
+    pub redirect_uri: Option<String>
+}
+
+impl OAuthSettings {
+    // A secret is only taken from the same place as the ID, they belong to the same app.
+    pub fn credentials(&self) -> Option<Credentials> {
+        match self.client_id.as_ref() {
+            Some(id) => Some(Credentials { id: id.clone(), secret: self.client_secret.clone() }),
+            None => Credentials::from_env()
+        }
+    }
+
+    pub fn oauth(&self, scopes: HashSet<String>) -> OAuth {
+        let redirect_uri = self.redirect_uri.clone()
+            .or_else(|| OAuth::from_env(HashSet::new()).map(| oauth | oauth.redirect_uri))
+            .unwrap_or_else(|| DEFAULT_REDIRECT_URI.to_string())
+        ;
+
+        OAuth { redirect_uri, scopes, ..Default::default() }
+    }
+}
+
+// Gives the client a token, either the one saved from the last login or a new one through the browser.
+pub async fn authorize<C: OAuthClient>(client: &mut C, saved_token: Option<Token>, authorize_url: impl FnOnce(&mut C) -> ClientResult<String>) -> Result<Token> {
+    if let Some(saved_token) = saved_token {
+        let token = client.get_token();
+        *token.lock().await.map_err(|_| APILoginError::Token)? = Some(saved_token.clone());
+
+        return Ok(saved_token);
+    }
+
+    let url = authorize_url(client)?;
+    let listener = CallbackListener::bind(client.get_oauth())?;
+
+    webbrowser::open(&url)?;
+
+    // Waiting on the browser blocks, so keep it off the runtime's threads.
+    let code = tokio::task::spawn_blocking(move || listener.wait_for_code(CALLBACK_TIMEOUT))
+        .await
+        .map_err(std::io::Error::other)??
+    ;
+
+    client.request_token(&code).await?;
+
+    let token = client.get_token();
+    let token_lock = token.lock().await.map_err(|_| APILoginError::Token)?;
+
+    token_lock.clone().ok_or_else(|| APILoginError::Token.into())
+}
+
+// Serves the redirect URI, where Spotify sends the user back to with an authorization code.
+pub struct CallbackListener {
+    server: Server,
+    path: String,
+    // Sent along with the authorization request, the callback has to have it too.
+    state: String
+}
+
+impl CallbackListener {
+    // Only ever listens on loopback, the code isn't meant for anyone else on the network.
+    pub fn bind(oauth: &OAuth) -> Result<CallbackListener> {
+        let url = Url::parse(&oauth.redirect_uri).map_err(|_| APILoginError::RedirectUri)?;
+
+        let ip: IpAddr = match url.host() {
+            Some(Host::Domain("localhost")) => Ipv4Addr::LOCALHOST.into(),
+            Some(Host::Ipv4(ip)) if ip.is_loopback() => ip.into(),
+            Some(Host::Ipv6(ip)) if ip.is_loopback() => ip.into(),
+            _ => return Err(APILoginError::RedirectUri.into())
+        };
+
+        let port = url.port_or_known_default().ok_or(APILoginError::RedirectUri)?;
+
+        if url.scheme() != "http" {
+            return Err(APILoginError::RedirectUri.into());
+        }
+
+        let server = Server::http(SocketAddr::new(ip, port)).map_err(std::io::Error::other)?;
+
+        Ok(CallbackListener {
+            server,
+            path: url.path().to_string(),
+            state: oauth.state.clone()
+        })
+    }
+
+    // Blocks until the callback comes in and returns its code, answering the browser with how it went.
+    pub fn wait_for_code(&self, timeout: Duration) -> Result<String> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            let request = match self.server.recv_timeout(remaining)? {
+                Some(request) => request,
+                None => return Err(APILoginError::CallbackTimeout.into())
+            };
+
+            match parse_callback(request.url(), &self.path, &self.state) {
+                // Anyone can make the browser load the callback, that's no reason to stop waiting for the real one.
+                Some(Err(TaskError::Login(APILoginError::StateMismatch))) => {
+                    let page = html_response("This login wasn't started by espot-rs.");
+                    let _ = request.respond(page.with_status_code(400));
+                }
+                Some(result) => {
+                    let page = match result.as_ref() {
+                        Ok(_) => String::from("Logged in, you can close this tab and go back to espot-rs."),
+                        Err(e) => format!("Couldn't log in to espot-rs. {}", e)
+                    };
+
+                    let _ = request.respond(html_response(&page));
+                    return result;
+                }
+                // Browsers also like asking for a favicon and such.
+                None => {
+                    let _ = request.respond(Response::empty(404));
+                }
+            }
+        }
+    }
+}
+
+// None if the request isn't for the callback at all.
+fn parse_callback(request_url: &str, path: &str, state: &str) -> Option<Result<String>> {
+    let url = Url::parse("http://127.0.0.1").ok()?.join(request_url).ok()?;
+
+    if url.path() != path {
+        return None;
+    }
+
+    let mut code = None;
+    let mut error = None;
+    let mut callback_state = None;
+
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "code" => code = Some(value.into_owned()),
+            "error" => error = Some(value.into_owned()),
+            "state" => callback_state = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    // Checked first, a callback we didn't ask for shouldn't be trusted with anything.
+    if callback_state.as_deref() != Some(state) {
+        return Some(Err(APILoginError::StateMismatch.into()));
+    }
+
+    if let Some(error) = error {
+        return Some(Err(APILoginError::Denied(error).into()));
+    }
+
+    Some(code.ok_or_else(|| APILoginError::Denied(String::from("no authorization code")).into()))
+}
+
+fn html_response(message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    let page = format!("<!DOCTYPE html><html><head><title>espot-rs</title></head><body><p>{}</p></body></html>", escape_html(message));
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..]).unwrap();
+
+    Response::from_string(page).with_header(content_type)
+}
+
+// Error messages can come from the callback's query, which is anyone's to write.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c)
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use rspotify::clients::BaseClient;
+
+    use super::*;
+    use super::super::TaskError;
+
+    fn test_oauth(redirect_uri: &str) -> OAuth {
+        OAuth {
+            redirect_uri: redirect_uri.to_string(),
+            state: String::from("state"),
+            ..Default::default()
+        }
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, addr).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        response
+    }
+
+    #[test]
+    fn settings_take_priority_over_the_environment() {
+        let settings = OAuthSettings {
+            client_id: Some(String::from("id")),
+            client_secret: Some(String::from("secret")),
+            redirect_uri: Some(String::from("http://127.0.0.1:8888/callback"))
+        };
+
+        let credentials = settings.credentials().unwrap();
+        assert_eq!(credentials.id, "id");
+        assert_eq!(credentials.secret.as_deref(), Some("secret"));
+
+        let oauth = settings.oauth(rspotify::scopes!("playlist-read-private"));
+        assert_eq!(oauth.redirect_uri, "http://127.0.0.1:8888/callback");
+        assert!(oauth.scopes.contains("playlist-read-private"));
+    }
+
+    #[test]
+    fn secrets_are_optional() {
+        let settings = OAuthSettings {
+            client_id: Some(String::from("id")),
+            ..Default::default()
+        };
+
+        let credentials = settings.credentials().unwrap();
+        assert_eq!(credentials.id, "id");
+        assert!(credentials.secret.is_none());
+    }
+
+    #[test]
+    fn saved_tokens_skip_the_browser() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let mut client = rspotify::AuthCodePkceSpotify::default();
+
+        let saved_token = Token { access_token: String::from("token"), ..Default::default() };
+        let token = runtime.block_on(authorize(&mut client, Some(saved_token), |_| panic!("no URL needed"))).unwrap();
+
+        assert_eq!(token.access_token, "token");
+        assert_eq!(runtime.block_on(client.get_token().lock()).unwrap().as_ref().unwrap().access_token, "token");
+    }
+
+    #[test]
+    fn callbacks_are_parsed() {
+        let code = parse_callback("/callback?code=abc%3D&state=state", "/callback", "state").unwrap();
+        assert_eq!(code.unwrap(), "abc=");
+
+        let denied = parse_callback("/callback?error=access_denied&state=state", "/callback", "state").unwrap();
+        assert!(matches!(denied, Err(TaskError::Login(APILoginError::Denied(e))) if e == "access_denied"));
+
+        let forged = parse_callback("/callback?code=abc&state=other", "/callback", "state").unwrap();
+        assert!(matches!(forged, Err(TaskError::Login(APILoginError::StateMismatch))));
+
+        let stateless = parse_callback("/callback?code=abc", "/callback", "state").unwrap();
+        assert!(matches!(stateless, Err(TaskError::Login(APILoginError::StateMismatch))));
+
+        assert!(parse_callback("/favicon.ico", "/callback", "state").is_none());
+    }
+
+    #[test]
+    fn only_loopback_redirects_are_served() {
+        for uri in ["http://example.com:8888/callback", "http://0.0.0.0:8888/callback", "https://127.0.0.1:8888/callback", "callback"] {
+            let result = CallbackListener::bind(&test_oauth(uri));
+            assert!(matches!(result, Err(TaskError::Login(APILoginError::RedirectUri))), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn waits_for_the_callback() {
+        let listener = CallbackListener::bind(&test_oauth("http://127.0.0.1:0/spotify/callback")).unwrap();
+        let addr = listener.server.server_addr();
+
+        let browser = std::thread::spawn(move || {
+            let favicon = get(addr, "/favicon.ico");
+            let callback = get(addr, "/spotify/callback?code=abc&state=state");
+
+            (favicon, callback)
+        });
+
+        assert_eq!(listener.wait_for_code(Duration::from_secs(5)).unwrap(), "abc");
+
+        let (favicon, callback) = browser.join().unwrap();
+        assert!(favicon.starts_with("HTTP/1.1 404"));
+        assert!(callback.starts_with("HTTP/1.1 200"));
+        assert!(callback.contains("Logged in"));
+    }
+
+    #[test]
+    fn ignores_callbacks_with_the_wrong_state() {
+        let listener = CallbackListener::bind(&test_oauth("http://127.0.0.1:0/callback")).unwrap();
+        let addr = listener.server.server_addr();
+
+        let browser = std::thread::spawn(move || {
+            let forged = get(addr, "/callback?code=forged&state=other");
+            let callback = get(addr, "/callback?code=abc&state=state");
+
+            (forged, callback)
+        });
+
+        assert_eq!(listener.wait_for_code(Duration::from_secs(5)).unwrap(), "abc");
+
+        let (forged, callback) = browser.join().unwrap();
+        assert!(forged.starts_with("HTTP/1.1 400"));
+        assert!(callback.starts_with("HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn errors_are_escaped() {
+        let listener = CallbackListener::bind(&test_oauth("http://127.0.0.1:0/callback")).unwrap();
+        let addr = listener.server.server_addr();
+
+        let browser = std::thread::spawn(move || get(addr, "/callback?error=%3Cscript%3Ealert(1)%3C/script%3E&state=state"));
+
+        assert!(listener.wait_for_code(Duration::from_secs(5)).is_err());
+
+        let page = browser.join().unwrap();
+        assert!(!page.contains("<script>"));
+        assert!(page.contains("&lt;script&gt;"));
+    }
+
+    #[test]
+    fn gives_up_without_a_callback() {
+        let listener = CallbackListener::bind(&test_oauth("http://127.0.0.1:0/callback")).unwrap();
+        let result = listener.wait_for_code(Duration::from_millis(50));
+
+        assert!(matches!(result, Err(TaskError::Login(APILoginError::CallbackTimeout))));
+    }
+}
@@ -0,0 +1,184 @@
+use async_trait::async_trait;
+
+use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
+use librespot::metadata::{Album, Playlist, Metadata};
+use librespot::playback::player::Player;
+
+use rspotify::clients::OAuthClient;
+use rspotify::model::{Id, TrackId, PlaylistId, PlayableId, ArtistId, SimplifiedPlaylist, SearchResult, SearchType};
+
+use super::{error, Result, TrackInfo};
+
+
+// What the worker needs to know about a playlist before fetching the whole thing.
+#[derive(Clone, Debug)]
+pub struct PlaylistSummary {
+    pub id: String,
+    // Size, url.
+    pub images: Vec<(u32, String)>
+}
+
+impl From<SimplifiedPlaylist> for PlaylistSummary {
+    fn from(playlist: SimplifiedPlaylist) -> PlaylistSummary {
+        let images = playlist.images
+            .into_iter()
+            .map(| i | {
+                (i.width.unwrap_or_default(), i.url)
+            })
+            .collect()
+        ;
+
+        PlaylistSummary {
+            id: playlist.id.uri(),
+            images
+        }
+    }
+}
+
+// Spotify's web API and metadata, as used by the worker's tasks.
+#[async_trait]
+pub trait ApiBackend: Send + Sync {
+    async fn user_playlists(&self) -> Result<Vec<PlaylistSummary>>;
+    async fn featured_playlists(&self, limit: u32) -> Result<Vec<PlaylistSummary>>;
+
+    async fn playlist(&self, id: &str) -> Result<Playlist>;
+    // URIs of the album's tracks.
+    async fn album_tracks(&self, id: &str) -> Result<Vec<String>>;
+    // Tracks that can't be found are left out.
+    async fn tracks(&self, ids: &[String]) -> Result<Vec<TrackInfo>>;
+
+    async fn search(&self, query: &str, search_type: SearchType) -> Result<SearchResult>;
+    // URIs of tracks similar to the seeds.
+    async fn recommendations(&self, seed_tracks: &[String], limit: u32) -> Result<Vec<String>>;
+
+    async fn add_track_to_playlist(&self, track: &str, playlist: &str) -> Result<()>;
+    async fn remove_track_from_playlist(&self, track: &str, playlist: &str) -> Result<()>;
+}
+
+// Audio playback, mirroring the parts of librespot's Player the worker uses.
+pub trait PlayerBackend: Send {
+    fn load(&mut self, track_id: SpotifyId, start_playing: bool, position_ms: u32);
+    fn preload(&self, track_id: SpotifyId);
+
+    fn play(&self);
+    fn pause(&self);
+    fn stop(&self);
+    fn seek(&self, position_ms: u32);
+}
+
+// Works with any of rspotify's user clients, which only differ in how they're authorized.
+pub struct WebApi<C: OAuthClient> {
+    pub client: C,
+    pub session: Session
+}
+
+#[async_trait]
+impl<C: OAuthClient> ApiBackend for WebApi<C> {
+    async fn user_playlists(&self) -> Result<Vec<PlaylistSummary>> {
+        let playlists = self.client.current_user_playlists_manual(None, None).await?;
+        Ok(playlists.items.into_iter().map(PlaylistSummary::from).collect())
+    }
+
+    async fn featured_playlists(&self, limit: u32) -> Result<Vec<PlaylistSummary>> {
+        let featured = self.client.featured_playlists(None, None, None, Some(limit), None).await?;
+        Ok(featured.playlists.items.into_iter().map(PlaylistSummary::from).collect())
+    }
+
+    async fn playlist(&self, id: &str) -> Result<Playlist> {
+        let playlist_id = SpotifyId::from_uri(id).map_err(|_| error::WorkerError::BadSpotifyId)?;
+        Ok(Playlist::get(&self.session, playlist_id).await.map_err(|_| error::WorkerError::NoPlaylist)?)
+    }
+
+    async fn album_tracks(&self, id: &str) -> Result<Vec<String>> {
+        let album_id = SpotifyId::from_uri(id).map_err(|_| error::WorkerError::BadSpotifyId)?;
+        let album = Album::get(&self.session, album_id).await.map_err(|_| error::WorkerError::BadSpotifyId)?;
+
+        // Album metadata doesn't say what kind of ID its tracks are, so build the URIs by hand.
+        Ok(album.tracks.iter().map(| id | format!("spotify:track:{}", id.to_base62())).collect())
+    }
+
+    async fn tracks(&self, ids: &[String]) -> Result<Vec<TrackInfo>> {
+        let ids: Vec<TrackId> = ids.iter().filter_map(| id | TrackId::from_uri(id).ok()).collect();
+        let mut result = Vec::with_capacity(ids.len());
+
+        // The tracks endpoint accepts a maximum of 50 tracks at a time.
+        for ids_batch in ids.chunks(50) {
+            let api_response = self.client.tracks(&ids_batch.to_vec(), None).await?;
+            result.extend(api_response.into_iter().filter_map(TrackInfo::new));
+        }
+
+        Ok(result)
+    }
+
+    async fn search(&self, query: &str, search_type: SearchType) -> Result<SearchResult> {
+        Ok(self.client.search(query, &search_type, None, None, None, None).await?)
+    }
+
+    async fn recommendations(&self, seed_tracks: &[String], limit: u32) -> Result<Vec<String>> {
+        let seed_tracks: Vec<TrackId> = seed_tracks.iter().filter_map(| id | TrackId::from_uri(id).ok()).collect();
+        let seed_artists: Option<&Vec<ArtistId>> = None;
+        let seed_genres: Option<Vec<&str>> = None;
+
+        let results = self.client.recommendations(
+            None,
+            seed_artists,
+            seed_genres,
+            Some(&seed_tracks),
+            None,
+            Some(limit)
+        ).await?;
+
+        let tracks = results.tracks
+            .into_iter()
+            .filter_map(| t | t.id)
+            .map(| id | id.uri())
+            .collect()
+        ;
+
+        Ok(tracks)
+    }
+
+    async fn add_track_to_playlist(&self, track: &str, playlist: &str) -> Result<()> {
+        let track_id = TrackId::from_uri(track).map_err(|_| error::WorkerError::BadSpotifyId)?;
+        let playlist_id = PlaylistId::from_uri(playlist).map_err(|_| error::WorkerError::BadSpotifyId)?;
+
+        let items: Vec<&dyn PlayableId> = vec![&track_id];
+
+        self.client.playlist_add_items(&playlist_id, items, None).await.map(|_| Ok(()))?
+    }
+
+    async fn remove_track_from_playlist(&self, track: &str, playlist: &str) -> Result<()> {
+        let playlist_id = PlaylistId::from_uri(playlist).map_err(|_| error::WorkerError::BadSpotifyId)?;
+        let track_id = TrackId::from_uri(track).map_err(|_| error::WorkerError::BadSpotifyId)?;
+        let track_ids: Vec<&dyn PlayableId> = vec![&track_id];
+
+        self.client.playlist_remove_all_occurrences_of_items(&playlist_id, track_ids, None).await.map(|_| Ok(()))?
+    }
+}
+
+impl PlayerBackend for Player {
+    fn load(&mut self, track_id: SpotifyId, start_playing: bool, position_ms: u32) {
+        Player::load(self, track_id, start_playing, position_ms);
+    }
+
+    fn preload(&self, track_id: SpotifyId) {
+        Player::preload(self, track_id);
+    }
+
+    fn play(&self) {
+        Player::play(self);
+    }
+
+    fn pause(&self) {
+        Player::pause(self);
+    }
+
+    fn stop(&self) {
+        Player::stop(self);
+    }
+
+    fn seek(&self, position_ms: u32) {
+        Player::seek(self, position_ms);
+    }
+}
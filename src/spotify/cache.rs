@@ -7,51 +7,51 @@ use rspotify::model::FullTrack;
 use serde::{Deserialize, Serialize};
 
 pub struct CacheHandler {
-    http_client: Client,
-
     cache_dir: PathBuf,
     cached_tracks: HashMap<String, TrackInfo>,
 }
 
 impl CacheHandler {
     pub fn init(cache_dir: PathBuf) -> CacheHandler {
-        let http_client = Client::new();
-
         let cache_data_path = cache_dir.join("tracks.ron");
         let cache_data = std::fs::read_to_string(&cache_data_path).unwrap_or_default();
         let cached_tracks = ron::from_str(&cache_data).unwrap_or_default();
 
         CacheHandler {
-            http_client,
-
             cache_dir,
             cached_tracks
         }
     }
 
     pub fn get_track_info(&self, id: &str) -> Option<TrackInfo> {
-        if let Some(track) = self.cached_tracks.get(id) {
-            // This should already be there, but making sure never killed anyone.
-            futures_lite::future::block_on(self.cache_cover_image(&track.album_id, &track.album_images));
-            
-            Some(track.clone())
-        }
-        else {
-            None
-        }
+        self.cached_tracks.get(id).cloned()
     }
 
-    pub fn cache_track_info(&mut self, track: FullTrack) -> Option<TrackInfo> {
-        if let Some(track) = TrackInfo::new(track) {
-            let id = track.id.clone();
-
-            futures_lite::future::block_on(self.cache_cover_image(&track.album_id, &track.album_images));
-            self.cached_tracks.insert(id, track.clone());
+    pub fn cache_track_info(&mut self, track: TrackInfo) {
+        self.cached_tracks.insert(track.id.clone(), track);
+    }
 
-            Some(track)
+    pub async fn save_cache(&self) {
+        if let Ok(data) = ron::ser::to_string_pretty(&self.cached_tracks, ron::ser::PrettyConfig::default()) {
+            if let Err(e) = fs::write(self.cache_dir.join("tracks.ron"), data).await {
+                println!("Error saving api cache: {}", e);
+            }
         }
-        else {
-            None
+    }
+}
+
+// Downloads covers into the cache dir. Kept apart from the track cache, so downloads don't keep it locked.
+#[derive(Clone)]
+pub struct CoverCache {
+    http_client: Client,
+    cache_dir: PathBuf
+}
+
+impl CoverCache {
+    pub fn new(cache_dir: PathBuf) -> CoverCache {
+        CoverCache {
+            http_client: Client::new(),
+            cache_dir
         }
     }
 
@@ -78,14 +78,6 @@ impl CacheHandler {
             }
         }
     }
-
-    pub async fn save_cache(&self) {
-        if let Ok(data) = ron::ser::to_string_pretty(&self.cached_tracks, ron::ser::PrettyConfig::default()) {
-            if let Err(e) = fs::write(self.cache_dir.join("tracks.ron"), data).await {
-                println!("Error saving api cache: {}", e);
-            }
-        }
-    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
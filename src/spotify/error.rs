@@ -1,11 +1,18 @@
 use std::error;
 use std::fmt::Display;
 
+use librespot::core::session::SessionError;
+
 #[derive(Debug)]
 pub enum APILoginError {
-    OAuth,
     Token,
     Credentials,
+
+    RedirectUri,
+    // What Spotify sent back instead of a code, like access_denied.
+    Denied(String),
+    StateMismatch,
+    CallbackTimeout,
 }
 
 impl error::Error for APILoginError {}
@@ -13,9 +20,13 @@ impl error::Error for APILoginError {}
 impl Display for APILoginError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            APILoginError::OAuth => write!(f, "Failed to load OAuth data from .env file"),
             APILoginError::Token => write!(f, "Failed to parse response token"),
-            APILoginError::Credentials => write!(f, "Failed to load credentials from .env file"),
+            APILoginError::Credentials => write!(f, "No client ID was set in the settings or the .env file"),
+
+            APILoginError::RedirectUri => write!(f, "The redirect URI has to be an http:// address on this computer"),
+            APILoginError::Denied(e) => write!(f, "Spotify didn't authorize the login ({})", e),
+            APILoginError::StateMismatch => write!(f, "The authorization callback didn't come from this login attempt"),
+            APILoginError::CallbackTimeout => write!(f, "Timed out waiting for the authorization callback"),
         }
     }
 }
@@ -23,10 +34,15 @@ impl Display for APILoginError {
 #[derive(Debug)]
 pub enum WorkerError {
     NoAPIClient,
+    NoMixer,
+    NoAudioBackend,
     NoSpotifyPlayer,
-    NoSpotifySession,
 
+    NoPlaylist,
     BadSpotifyId,
+
+    EmptyQueue,
+    BadQueueIndex,
 }
 
 impl error::Error for WorkerError {}
@@ -35,10 +51,81 @@ impl Display for WorkerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             WorkerError::NoAPIClient => write!(f, "A Spotify API client wasn't created."),
+            WorkerError::NoMixer => write!(f, "No audio mixer is available."),
+            WorkerError::NoAudioBackend => write!(f, "The selected audio backend isn't available."),
             WorkerError::NoSpotifyPlayer => write!(f, "A Spotify player wasn't created."),
-            WorkerError::NoSpotifySession => write!(f, "A Spotify session wasn't created."),
 
-            WorkerError::BadSpotifyId => write!(f, "An invalid Spotify ID was provided.")
+            WorkerError::NoPlaylist => write!(f, "The playlist couldn't be found or has no tracks."),
+            WorkerError::BadSpotifyId => write!(f, "An invalid Spotify ID was provided."),
+
+            WorkerError::EmptyQueue => write!(f, "There are no tracks in the queue."),
+            WorkerError::BadQueueIndex => write!(f, "The queue doesn't have a track at that position.")
         }
     }
 }
+
+#[derive(Debug)]
+pub enum TaskError {
+    Login(APILoginError),
+    Worker(WorkerError),
+
+    Api(rspotify::ClientError),
+    Session(SessionError),
+    Io(std::io::Error)
+}
+
+impl error::Error for TaskError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            TaskError::Login(e) => Some(e),
+            TaskError::Worker(e) => Some(e),
+
+            TaskError::Api(e) => Some(e),
+            TaskError::Session(e) => Some(e),
+            TaskError::Io(e) => Some(e)
+        }
+    }
+}
+
+impl Display for TaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskError::Login(e) => write!(f, "Login failed: {}", e),
+            TaskError::Worker(e) => write!(f, "{}", e),
+
+            TaskError::Api(e) => write!(f, "Spotify API error: {}", e),
+            TaskError::Session(e) => write!(f, "Spotify session error: {}", e),
+            TaskError::Io(e) => write!(f, "I/O error: {}", e)
+        }
+    }
+}
+
+impl From<APILoginError> for TaskError {
+    fn from(e: APILoginError) -> TaskError {
+        TaskError::Login(e)
+    }
+}
+
+impl From<WorkerError> for TaskError {
+    fn from(e: WorkerError) -> TaskError {
+        TaskError::Worker(e)
+    }
+}
+
+impl From<rspotify::ClientError> for TaskError {
+    fn from(e: rspotify::ClientError) -> TaskError {
+        TaskError::Api(e)
+    }
+}
+
+impl From<SessionError> for TaskError {
+    fn from(e: SessionError) -> TaskError {
+        TaskError::Session(e)
+    }
+}
+
+impl From<std::io::Error> for TaskError {
+    fn from(e: std::io::Error) -> TaskError {
+        TaskError::Io(e)
+    }
+}
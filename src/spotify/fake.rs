@@ -0,0 +1,192 @@
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use async_trait::async_trait;
+
+use librespot::metadata::Playlist;
+use librespot::core::spotify_id::SpotifyId;
+
+use rspotify::model::{Page, SearchResult, SearchType};
+
+use super::backend::{ApiBackend, PlayerBackend, PlaylistSummary};
+use super::{error, Result, TaskError, TrackInfo};
+
+
+// Everything is kept in memory, and nothing ever touches the network.
+#[derive(Default)]
+pub struct FakeApi {
+    pub playlists: HashMap<String, Playlist>,
+    pub albums: HashMap<String, Vec<String>>,
+    pub tracks: HashMap<String, TrackInfo>,
+
+    // How many tracks were requested through `tracks`, to check what the cache saved us.
+    pub tracks_requested: AtomicUsize,
+    // Makes every call fail.
+    pub failing: AtomicBool
+}
+
+impl FakeApi {
+    pub fn add_track(&mut self, track: TrackInfo) {
+        self.tracks.insert(track.id.clone(), track);
+    }
+
+    pub fn add_playlist(&mut self, id: &str, tracks: &[TrackInfo]) {
+        let playlist = Playlist {
+            revision: Vec::new(),
+            user: String::from("user"),
+            name: format!("Playlist {}", id),
+            tracks: tracks.iter().map(| t | SpotifyId::from_uri(&t.id).unwrap()).collect()
+        };
+
+        for track in tracks {
+            self.add_track(track.clone());
+        }
+
+        self.playlists.insert(id.to_string(), playlist);
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.failing.load(Ordering::SeqCst) {
+            Err(TaskError::Io(std::io::Error::other("fake API failure")))
+        }
+        else {
+            Ok(())
+        }
+    }
+}
+
+#[async_trait]
+impl ApiBackend for FakeApi {
+    async fn user_playlists(&self) -> Result<Vec<PlaylistSummary>> {
+        self.check()?;
+        Ok(self.playlists.keys().map(| id | PlaylistSummary { id: id.clone(), images: Vec::new() }).collect())
+    }
+
+    async fn featured_playlists(&self, limit: u32) -> Result<Vec<PlaylistSummary>> {
+        let mut playlists = self.user_playlists().await?;
+        playlists.truncate(limit as usize);
+
+        Ok(playlists)
+    }
+
+    async fn playlist(&self, id: &str) -> Result<Playlist> {
+        self.check()?;
+        Ok(self.playlists.get(id).cloned().ok_or(error::WorkerError::NoPlaylist)?)
+    }
+
+    async fn album_tracks(&self, id: &str) -> Result<Vec<String>> {
+        self.check()?;
+        Ok(self.albums.get(id).cloned().ok_or(error::WorkerError::BadSpotifyId)?)
+    }
+
+    async fn tracks(&self, ids: &[String]) -> Result<Vec<TrackInfo>> {
+        self.check()?;
+        self.tracks_requested.fetch_add(ids.len(), Ordering::SeqCst);
+
+        Ok(ids.iter().filter_map(| id | self.tracks.get(id).cloned()).collect())
+    }
+
+    // Nothing's searchable, so every search comes back empty.
+    async fn search(&self, _query: &str, _search_type: SearchType) -> Result<SearchResult> {
+        self.check()?;
+
+        let page = Page {
+            href: String::new(),
+            items: Vec::new(),
+            limit: 0,
+            next: None,
+            offset: 0,
+            previous: None,
+            total: 0
+        };
+
+        Ok(SearchResult::Tracks(page))
+    }
+
+    async fn recommendations(&self, seed_tracks: &[String], limit: u32) -> Result<Vec<String>> {
+        self.check()?;
+
+        let tracks = self.tracks.keys()
+            .filter(| id | !seed_tracks.contains(id))
+            .take(limit as usize)
+            .cloned()
+            .collect()
+        ;
+
+        Ok(tracks)
+    }
+
+    async fn add_track_to_playlist(&self, _track: &str, _playlist: &str) -> Result<()> {
+        self.check()
+    }
+
+    async fn remove_track_from_playlist(&self, _track: &str, _playlist: &str) -> Result<()> {
+        self.check()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FakePlayerState {
+    pub loaded: Vec<SpotifyId>,
+    pub preloaded: Vec<SpotifyId>,
+
+    pub playing: bool,
+    pub position_ms: u32
+}
+
+// Records what it was asked to do. Tests keep a handle to the state to inspect it.
+#[derive(Clone, Default)]
+pub struct FakePlayer {
+    pub state: Arc<Mutex<FakePlayerState>>
+}
+
+impl PlayerBackend for FakePlayer {
+    fn load(&mut self, track_id: SpotifyId, start_playing: bool, position_ms: u32) {
+        let mut state = self.state.lock().unwrap();
+
+        state.loaded.push(track_id);
+        state.playing = start_playing;
+        state.position_ms = position_ms;
+    }
+
+    fn preload(&self, track_id: SpotifyId) {
+        self.state.lock().unwrap().preloaded.push(track_id);
+    }
+
+    fn play(&self) {
+        self.state.lock().unwrap().playing = true;
+    }
+
+    fn pause(&self) {
+        self.state.lock().unwrap().playing = false;
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().playing = false;
+    }
+
+    fn seek(&self, position_ms: u32) {
+        self.state.lock().unwrap().position_ms = position_ms;
+    }
+}
+
+// Tracks only differ in their ID and name, `n` has to be unique for the IDs to be.
+pub fn test_track(n: u32) -> TrackInfo {
+    TrackInfo {
+        id: format!("spotify:track:{:0>22}", n),
+
+        name: format!("Track {}", n),
+        duration_ms: 180_000,
+
+        artists: vec![String::from("Artist")],
+
+        album_id: String::from("spotify:album:album"),
+        album_name: String::from("Album"),
+        album_images: Vec::new()
+    }
+}
+
+pub fn test_tracks(count: u32) -> Vec<TrackInfo> {
+    (0..count).map(test_track).collect()
+}
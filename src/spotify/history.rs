@@ -0,0 +1,191 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::TrackInfo;
+
+// Older entries are dropped past this, the whole file gets rewritten on every track.
+const MAX_ENTRIES: usize = 200;
+
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HistoryEntry {
+    pub track: TrackInfo,
+    // Seconds since the UNIX epoch.
+    pub played_at: u64
+}
+
+// Every track that started playing, oldest first, kept in the cache dir across sessions.
+pub struct PlayHistory {
+    path: PathBuf,
+    entries: Vec<HistoryEntry>,
+
+    // How far back from the newest entry the user went with previous_track.
+    steps_back: usize
+}
+
+impl PlayHistory {
+    pub fn load(path: PathBuf) -> PlayHistory {
+        let data = std::fs::read_to_string(&path).unwrap_or_default();
+        let entries = ron::from_str(&data).unwrap_or_default();
+
+        PlayHistory {
+            path,
+            entries,
+
+            steps_back: 0
+        }
+    }
+
+    pub fn record(&mut self, track: TrackInfo) {
+        let played_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(| d | d.as_secs())
+            .unwrap_or_default()
+        ;
+
+        self.entries.push(HistoryEntry { track, played_at });
+        self.steps_back = 0;
+
+        if self.entries.len() > MAX_ENTRIES {
+            self.entries.drain(..self.entries.len() - MAX_ENTRIES);
+        }
+
+        self.save();
+    }
+
+    // The track played before the one we're at, if there's one.
+    pub fn previous(&mut self) -> Option<&TrackInfo> {
+        if self.steps_back + 1 < self.entries.len() {
+            self.steps_back += 1;
+            Some(&self.entries[self.entries.len() - 1 - self.steps_back].track)
+        }
+        else {
+            None
+        }
+    }
+
+    // What next() would return, without moving.
+    pub fn peek_next(&self) -> Option<&TrackInfo> {
+        if self.steps_back > 0 {
+            Some(&self.entries[self.entries.len() - self.steps_back].track)
+        }
+        else {
+            None
+        }
+    }
+
+    // After going back, the track that was played after the one we're at.
+    pub fn next(&mut self) -> Option<&TrackInfo> {
+        if self.steps_back > 0 {
+            self.steps_back -= 1;
+            Some(&self.entries[self.entries.len() - 1 - self.steps_back].track)
+        }
+        else {
+            None
+        }
+    }
+
+    // The last `limit` different tracks played, most recent first.
+    pub fn recent(&self, limit: usize) -> Vec<TrackInfo> {
+        let mut result: Vec<TrackInfo> = Vec::new();
+
+        for entry in self.entries.iter().rev() {
+            if result.len() == limit {
+                break;
+            }
+
+            if !result.iter().any(| t | t.id == entry.track.id) {
+                result.push(entry.track.clone());
+            }
+        }
+
+        result
+    }
+
+    fn save(&self) {
+        if let Ok(data) = ron::to_string(&self.entries) {
+            if let Err(e) = std::fs::write(&self.path, data) {
+                println!("Error saving playback history: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::fake::test_track;
+
+    fn test_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("espot-rs-history-{}-{}.ron", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        path
+    }
+
+    #[test]
+    fn walks_back_and_forward() {
+        let path = test_path("walk");
+        let mut history = PlayHistory::load(path.clone());
+
+        assert!(history.previous().is_none());
+        assert!(history.next().is_none());
+
+        for n in 0..3 {
+            history.record(test_track(n));
+        }
+
+        assert_eq!(history.previous().unwrap().id, test_track(1).id);
+        assert_eq!(history.previous().unwrap().id, test_track(0).id);
+        assert!(history.previous().is_none());
+
+        assert_eq!(history.peek_next().unwrap().id, test_track(1).id);
+        assert_eq!(history.next().unwrap().id, test_track(1).id);
+        assert_eq!(history.next().unwrap().id, test_track(2).id);
+        assert!(history.peek_next().is_none());
+        assert!(history.next().is_none());
+
+        // Playing something new while back in history puts us at the end again.
+        history.previous();
+        history.record(test_track(3));
+        assert!(history.next().is_none());
+        assert_eq!(history.previous().unwrap().id, test_track(2).id);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn recent_tracks_are_unique_and_newest_first() {
+        let path = test_path("recent");
+        let mut history = PlayHistory::load(path.clone());
+
+        for n in [0, 1, 0, 2, 2] {
+            history.record(test_track(n));
+        }
+
+        let recent: Vec<String> = history.recent(10).into_iter().map(| t | t.id).collect();
+        assert_eq!(recent, vec![test_track(2).id, test_track(0).id, test_track(1).id]);
+        assert_eq!(history.recent(1).len(), 1);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn persists_and_drops_old_entries() {
+        let path = test_path("persist");
+        let mut history = PlayHistory::load(path.clone());
+
+        for n in 0..(MAX_ENTRIES as u32 + 5) {
+            history.record(test_track(n));
+        }
+
+        let history = PlayHistory::load(path.clone());
+        assert_eq!(history.entries.len(), MAX_ENTRIES);
+        assert_eq!(history.entries[0].track.id, test_track(5).id);
+        assert!(history.entries.iter().all(| e | e.played_at > 0));
+
+        let _ = std::fs::remove_file(path);
+    }
+}
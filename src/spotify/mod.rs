@@ -1,57 +1,126 @@
 mod cache;
 mod error;
-
-use tiny_http::Server;
-use nanorand::{Rng, WyRand};
+mod tasks;
+mod queue;
+mod history;
+mod session;
+mod backend;
+mod audio;
+mod auth;
+
+#[cfg(test)]
+pub mod fake;
+#[cfg(test)]
+mod tests;
+
+use std::sync::Arc;
+use std::path::PathBuf;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use nanorand::WyRand;
 use serde::{Deserialize, Serialize};
 
 use tokio::runtime::Runtime;
-use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex};
 
 use librespot::core::session::Session;
 use librespot::core::config::SessionConfig;
 use librespot::core::spotify_id::SpotifyId;
 use librespot::core::authentication::Credentials;
 
-use librespot::metadata::{Playlist, Metadata};
+use librespot::metadata::Playlist;
 
-use librespot::playback::config;
+use librespot::playback::mixer::{self, Mixer, MixerConfig};
 use librespot::playback::player::{Player, PlayerEvent};
 
 use rspotify::Token;
 use rspotify::auth_code::AuthCodeSpotify;
-use rspotify::clients::{OAuthClient, BaseClient};
-use rspotify::model::{Id, TrackId, PlaylistId, PlayableId, ArtistId, SimplifiedPlaylist, SearchResult, SearchType};
-
-use cache::CacheHandler;
+use rspotify::auth_code_pkce::AuthCodePkceSpotify;
+use rspotify::model::{SearchResult, SearchType};
+
+use cache::{CacheHandler, CoverCache};
+use tasks::TaskContext;
+use queue::PlayQueue;
+use history::PlayHistory;
+use session::SavedSession;
+use backend::{ApiBackend, PlayerBackend, WebApi};
 pub use cache::TrackInfo;
+pub use queue::QueueEntry;
+pub use audio::{AudioSettings, Bitrate, NormalisationMethod, NormalisationType, SampleFormat};
+pub use audio::backends as audio_backends;
+pub use auth::{OAuthSettings, DEFAULT_REDIRECT_URI};
+pub use error::TaskError;
+
 
+type TaskTx = mpsc::UnboundedSender<WorkerRequest>;
+type TaskRx = mpsc::UnboundedReceiver<WorkerRequest>;
 
-type TaskTx = mpsc::UnboundedSender<WorkerTask>;
-type TaskRx = mpsc::UnboundedReceiver<WorkerTask>;
+// Results are tagged with the request they answer, if they answer one at all.
+type TaskResultTx = mpsc::UnboundedSender<(Option<RequestId>, WorkerResult)>;
+type TaskResultRx = mpsc::UnboundedReceiver<(Option<RequestId>, WorkerResult)>;
 
-type TaskResultTx = mpsc::UnboundedSender<WorkerResult>;
-type TaskResultRx = mpsc::UnboundedReceiver<WorkerResult>;
 
 type StateTx = broadcast::Sender<PlayerStateUpdate>;
 type StateRx = broadcast::Receiver<PlayerStateUpdate>;
 
+// Positions come in every second and only the latest one matters, so they don't share the lossy state channel.
+type PositionTx = watch::Sender<Option<PlaybackPosition>>;
+type PositionRx = watch::Receiver<Option<PlaybackPosition>>;
+
 type ControlTx = mpsc::UnboundedSender<PlayerControl>;
 type ControlRx = mpsc::UnboundedReceiver<PlayerControl>;
 
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+type ShutdownTx = oneshot::Sender<()>;
+type ShutdownRx = oneshot::Receiver<()>;
+
+type Result<T> = std::result::Result<T, TaskError>;
+
+// Called whenever the worker has something new for the UI, so it doesn't have to keep polling.
+pub type RepaintCallback = Arc<dyn Fn() + Send + Sync>;
+
+// How often the playback position is broadcast while a track is playing.
+const POSITION_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+// How often the session is saved while a track is playing, in case the app never gets to exit cleanly.
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(15);
+// How long exiting waits on the worker, it could be stuck on something like a pending login.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct LoginData {
     pub username: String,
     pub password: String,
-    pub api_token: Option<Token>
+    pub api_token: Option<Token>,
+    // Comes from the config, so it's left out of what's saved to the keyring.
+    #[serde(skip)]
+    pub oauth: OAuthSettings
+}
+
+// Identifies a task sent to the worker, so its result can be matched with it (or ignored).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RequestId(u64);
+
+impl RequestId {
+    pub fn next() -> RequestId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        RequestId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug)]
+pub enum WorkerRequest {
+    Task(RequestId, WorkerTask),
+    // Stops a task that's still running, nothing gets sent back for it.
+    Cancel(RequestId)
 }
 
 #[derive(Debug)]
 pub enum WorkerTask {
-    Login(LoginData),
+    // Boxed, it dwarfs every other task.
+    Login(Box<LoginData>),
     
     GetUserPlaylists,
     GetFeaturedPlaylists,
@@ -61,12 +130,81 @@ pub enum WorkerTask {
     Search(String, SearchType),
 
     AddTrackToPlaylist(String, String),
-    RemoveTrackFromPlaylist(String, String)
+    RemoveTrackFromPlaylist(String, String),
+
+    GetRecentlyPlayed
+}
+
+impl WorkerTask {
+    pub fn kind(&self) -> TaskKind {
+        match self {
+            WorkerTask::Login(_) => TaskKind::Login,
+
+            WorkerTask::GetUserPlaylists => TaskKind::GetUserPlaylists,
+            WorkerTask::GetFeaturedPlaylists => TaskKind::GetFeaturedPlaylists,
+            WorkerTask::GetPlaylistTracksInfo(_) => TaskKind::GetPlaylistTracksInfo,
+            WorkerTask::GetRecommendationsForPlaylist(_) => TaskKind::GetRecommendationsForPlaylist,
+
+            WorkerTask::Search(..) => TaskKind::Search,
+
+            WorkerTask::AddTrackToPlaylist(..) => TaskKind::AddTrackToPlaylist,
+            WorkerTask::RemoveTrackFromPlaylist(..) => TaskKind::RemoveTrackFromPlaylist,
+
+            WorkerTask::GetRecentlyPlayed => TaskKind::GetRecentlyPlayed
+        }
+    }
+}
+
+// Identifies the task (or player action) a WorkerResult::Error comes from,
+// so the UI can tell what failed without holding on to the original task.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskKind {
+    Login,
+
+    GetUserPlaylists,
+    GetFeaturedPlaylists,
+    GetPlaylistTracksInfo,
+    GetRecommendationsForPlaylist,
+
+    Search,
+
+    AddTrackToPlaylist,
+    RemoveTrackFromPlaylist,
+
+    GetRecentlyPlayed,
+
+    StartPlaylist,
+    AddTrackToQueue,
+    ApplyAudioSettings
+}
+
+impl std::fmt::Display for TaskKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskKind::Login => write!(f, "Logging in"),
+
+            TaskKind::GetUserPlaylists => write!(f, "Fetching your playlists"),
+            TaskKind::GetFeaturedPlaylists => write!(f, "Fetching featured playlists"),
+            TaskKind::GetPlaylistTracksInfo => write!(f, "Fetching playlist tracks"),
+            TaskKind::GetRecommendationsForPlaylist => write!(f, "Fetching recommendations"),
+
+            TaskKind::Search => write!(f, "Searching"),
+
+            TaskKind::AddTrackToPlaylist => write!(f, "Adding track to playlist"),
+            TaskKind::RemoveTrackFromPlaylist => write!(f, "Removing track from playlist"),
+
+            TaskKind::GetRecentlyPlayed => write!(f, "Fetching recently played tracks"),
+
+            TaskKind::StartPlaylist => write!(f, "Starting playback"),
+            TaskKind::AddTrackToQueue => write!(f, "Adding track to queue"),
+            TaskKind::ApplyAudioSettings => write!(f, "Applying audio settings")
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum WorkerResult {
-    Login(Option<Token>),
+    Login(Token),
 
     UserPlaylists(Vec<(String, Playlist)>),
     FeaturedPlaylists(Vec<(String, Playlist)>),
@@ -74,9 +212,15 @@ pub enum WorkerResult {
     SearchResult(SearchResult),
 
     PlaylistTrackInfo(Vec<TrackInfo>),
-    PlaylistRecommendations(Vec<TrackInfo>)
+    PlaylistRecommendations(Vec<TrackInfo>),
+    // Most recent first.
+    RecentlyPlayed(Vec<TrackInfo>),
+
+    Error(TaskKind, TaskError)
 }
 
+// Some of these are only sent by the dbus server, which is optional.
+#[cfg_attr(not(all(target_os = "linux", feature = "mpris")), allow(dead_code))]
 #[derive(Debug)]
 pub enum PlayerControl {
     Play,
@@ -88,316 +232,660 @@ pub enum PlayerControl {
     StartPlaylistAtTrack(Vec<TrackInfo>, TrackInfo),
 
     NextTrack,
-    PreviousTrack
+    PreviousTrack,
+
+    // Absolute position, in milliseconds.
+    Seek(u32),
+    // Offset from the current position, in milliseconds.
+    SeekRelative(i64),
+
+    SetVolume(u16),
+    SetShuffle(bool),
+    SetRepeat(RepeatMode),
+    // Recreates the player with the new settings, without having to log in again.
+    SetAudioSettings(AudioSettings),
+    // Drops to the lowest bitrate, whatever the settings say.
+    SetDataSaver(bool),
+
+    // Index in the queue.
+    GoToTrack(usize),
+    RemoveTrack(usize),
+    // Track URI, index in the queue to insert it at, and whether to start playing it.
+    AddTrack(String, usize, bool),
+
+    PlayNext(TrackInfo),
+    AddToQueue(TrackInfo),
+    // From and to indices in the queue.
+    MoveTrack(usize, usize),
+    // Removes everything but the current track.
+    ClearQueue,
+
+    StartPlaylistById(String),
+    PlayUri(PlayableUri)
 }
 
+// Something that can be started through a single URI, like MPRIS' OpenUri does.
+#[cfg_attr(not(all(target_os = "linux", feature = "mpris")), allow(dead_code))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PlayableUri {
+    Track(String),
+    Album(String),
+    Playlist(String)
+}
+
+#[cfg_attr(not(all(target_os = "linux", feature = "mpris")), allow(dead_code))]
+impl PlayableUri {
+    // Accepts spotify: URIs and open.spotify.com links, normalizing both into a spotify: URI.
+    pub fn parse(uri: &str) -> Option<PlayableUri> {
+        let (kind, id) = {
+            if let Some(uri) = uri.strip_prefix("spotify:") {
+                uri.split_once(':')?
+            }
+            else {
+                let path = uri.strip_prefix("https://").or_else(|| uri.strip_prefix("http://"))?;
+                let path = path.strip_prefix("open.spotify.com/")?;
+
+                // Links usually carry a ?si= tracking parameter, which isn't part of the ID.
+                let path = path.split(['?', '#']).next()?;
+
+                // And localized ones have an intl-xx segment in front.
+                let path = match path.split_once('/') {
+                    Some((prefix, rest)) if prefix.starts_with("intl-") => rest,
+                    _ => path
+                };
+
+                path.split_once('/')?
+            }
+        };
+
+        SpotifyId::from_base62(id).ok()?;
+        let uri = format!("spotify:{}:{}", kind, id);
+
+        match kind {
+            "track" => Some(PlayableUri::Track(uri)),
+            "album" => Some(PlayableUri::Album(uri)),
+            "playlist" => Some(PlayableUri::Playlist(uri)),
+            _ => None
+        }
+    }
+}
+
+#[cfg_attr(not(all(target_os = "linux", feature = "mpris")), allow(dead_code))]
 #[derive(Clone, Debug)]
 pub enum PlayerStateUpdate {
     Paused,
     Resumed,
     Stopped,
+    // New position, in milliseconds.
+    Seeked(u32),
+    Volume(u16),
+    // What the player's streaming at, changes with the settings and data saver.
+    Bitrate(Bitrate),
+    Shuffle(bool),
+    Repeat(RepeatMode),
+    // Sent whenever the queue or the current track changes, along with the current track's index.
+    Queue(Vec<QueueEntry>, usize),
+    // ID and name of each of the user's playlists.
+    UserPlaylists(Vec<(String, String)>),
+    // ID of the playlist the queue was started from, if it was started from one by ID.
+    ActivePlaylist(Option<String>),
     EndOfTrack(TrackInfo)
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RepeatMode {
+    // Stop once the end of the queue is reached.
+    None,
+    // Keep playing the current track.
+    Track,
+    // Go back to the start of the queue once the end is reached.
+    #[default]
+    Playlist
+}
+
+#[derive(Clone, Debug)]
+pub struct PlaybackPosition {
+    pub track_id: String,
+    pub position_ms: u32,
+    // When position_ms was sampled, used to interpolate while playing.
+    pub timestamp: Instant,
+    pub playing: bool
+}
+
+impl PlaybackPosition {
+    pub fn current_position_ms(&self) -> u32 {
+        if self.playing {
+            self.position_ms + self.timestamp.elapsed().as_millis() as u32
+        }
+        else {
+            self.position_ms
+        }
+    }
+}
+
+// Sent back to the worker by tasks running on their own once they're done.
+enum TaskDone {
+    // Passed on to the UI as is.
+    Finished(RequestId, TaskKind, Result<Option<WorkerResult>>),
+    // Logging in ends with a session, which the worker still has to create a player for.
+    LoggedIn(RequestId, Result<LoginSession>),
+    // Tracks to start the queue with, and the playlist they came from.
+    QueueFetched(RequestId, Option<String>, Result<Vec<TrackInfo>>),
+    // A track to insert into the queue at the given index, and whether to play it.
+    TrackFetched(RequestId, usize, bool, Result<TrackInfo>)
+}
+
+struct LoginSession {
+    session: Session,
+    api: Arc<dyn ApiBackend>,
+    token: Token
+}
+
+// Waits for the next player event, or forever if there's no player yet.
+async fn next_player_event(events: &mut Option<mpsc::UnboundedReceiver<PlayerEvent>>) -> Option<PlayerEvent> {
+    match events.as_mut() {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await
+    }
+}
+
 pub struct SpotifyWorker {
-    api_client: Option<AuthCodeSpotify>,
-    api_cache_handler: CacheHandler,
+    context: TaskContext,
 
-    spotify_player: Option<Player>,
     spotify_session: Option<Session>,
+    spotify_mixer: Option<Box<dyn Mixer>>,
+    spotify_player: Option<Box<dyn PlayerBackend>>,
+    // Only around once logged in, since the player is created on login.
+    player_events: Option<mpsc::UnboundedReceiver<PlayerEvent>>,
 
-    state_tx: StateTx,
     control_rx: ControlRx,
 
     worker_task_rx: TaskRx,
     worker_result_tx: TaskResultTx,
 
+    running_tasks: HashMap<RequestId, JoinHandle<()>>,
+    task_done_tx: mpsc::UnboundedSender<TaskDone>,
+    task_done_rx: mpsc::UnboundedReceiver<TaskDone>,
+    // Only the queue asked for last gets played.
+    queue_request: Option<RequestId>,
+
+    position_tx: PositionTx,
+
     player_paused: bool,
-    player_current_track: usize,
-    player_tracks_queue: Vec<TrackInfo>
+    player_volume: u16,
+    player_audio_settings: AudioSettings,
+    player_data_saver: bool,
+    // Last position reported by the player, and when it was reported.
+    player_position_ms: u32,
+    player_position_instant: Instant,
+    player_position_last_update: Instant,
+
+    player_queue: PlayQueue,
+    player_history: PlayHistory,
+    // Where the queue and position are saved, to restore them on the next login.
+    session_path: PathBuf,
+    session_last_save: Instant,
+
+    cache_dir: PathBuf
 }
 
-impl SpotifyWorker {
-    pub fn start() -> (TaskTx, TaskResultRx, StateRx, StateRx, ControlTx) {
-        let cache_dir = dirs::cache_dir().unwrap().join("espot-rs");
+// Stops the worker, and waits a bit for it to finish saving the session.
+pub struct WorkerShutdown {
+    tx: ShutdownTx,
+    done_rx: std::sync::mpsc::Receiver<()>
+}
 
-        let (state_tx, state_rx) = broadcast::channel(5);
-        let (control_tx, control_rx) = mpsc::unbounded_channel();
+impl WorkerShutdown {
+    pub fn shutdown(self) {
+        let _ = self.tx.send(());
 
-        let (worker_task_tx, worker_task_rx) = mpsc::unbounded_channel();
-        let (worker_result_tx, worker_result_rx) = mpsc::unbounded_channel();
-
-        let state_rx_2 = state_tx.subscribe();
+        if self.done_rx.recv_timeout(SHUTDOWN_TIMEOUT).is_err() {
+            println!("The worker didn't stop in time, the session may not have been saved.");
+        }
+    }
+}
 
-        if let Err(err) = std::fs::create_dir_all(&cache_dir.join("audio")) {
+impl SpotifyWorker {
+    pub fn start(cache_dir: PathBuf, repaint: RepaintCallback) -> (TaskTx, TaskResultRx, StateRx, StateRx, PositionRx, ControlTx, WorkerShutdown) {
+        if let Err(err) = std::fs::create_dir_all(cache_dir.join("audio")) {
             match err.kind() {
                 std::io::ErrorKind::AlreadyExists => {},
                 _ => panic!("failed to create cache directory")
             }
         }
 
-        let api_cache_handler = CacheHandler::init(cache_dir);
+        let (worker, worker_task_tx, worker_result_rx, state_rx, control_tx) = SpotifyWorker::new(cache_dir, repaint);
+
+        let state_rx_2 = worker.context.state_tx.subscribe();
+        let position_rx = worker.position_tx.subscribe();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            let rt = Runtime::new().unwrap();
+            let mut worker = worker;
+
+            rt.block_on(worker.process_events(shutdown_rx));
+            let _ = done_tx.send(());
+
+            // Don't keep the app from exiting over tasks still waiting on something, like a login.
+            rt.shutdown_background();
+        });
+
+        let shutdown = WorkerShutdown {
+            tx: shutdown_tx,
+            done_rx
+        };
+
+        (worker_task_tx, worker_result_rx, state_rx, state_rx_2, position_rx, control_tx, shutdown)
+    }
+
+    fn new(cache_dir: PathBuf, repaint: RepaintCallback) -> (SpotifyWorker, TaskTx, TaskResultRx, StateRx, ControlTx) {
+        let (state_tx, state_rx) = broadcast::channel(16);
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+
+        let (worker_task_tx, worker_task_rx) = mpsc::unbounded_channel();
+        let (worker_result_tx, worker_result_rx) = mpsc::unbounded_channel();
+
+        let (task_done_tx, task_done_rx) = mpsc::unbounded_channel();
+        let (position_tx, _) = watch::channel(None);
+
+        let context = TaskContext {
+            api: None,
+            api_cache_handler: Arc::new(Mutex::new(CacheHandler::init(cache_dir.clone()))),
+            covers: CoverCache::new(cache_dir.clone()),
+
+            state_tx,
+            repaint
+        };
 
         let worker = SpotifyWorker {
-            api_client: None,
-            api_cache_handler,
+            context,
 
-            spotify_player: None,
             spotify_session: None,
+            spotify_mixer: None,
+            spotify_player: None,
+            player_events: None,
 
-            state_tx,
             control_rx,
 
             worker_task_rx,
             worker_result_tx,
 
-            player_paused: true,
-            player_current_track: 0,
-            player_tracks_queue: Vec::new()
-        };
+            running_tasks: HashMap::new(),
+            queue_request: None,
+            task_done_tx,
+            task_done_rx,
 
-        std::thread::spawn(move || {
-            let rt = Runtime::new().unwrap();
-            let mut worker = worker;
+            position_tx,
 
-            rt.block_on(worker.process_events());
-        });
+            player_paused: true,
+            player_volume: u16::MAX,
+            player_audio_settings: AudioSettings::default(),
+            player_data_saver: false,
+            player_position_ms: 0,
+            player_position_instant: Instant::now(),
+            player_position_last_update: Instant::now(),
+
+            player_queue: PlayQueue::default(),
+            player_history: PlayHistory::load(cache_dir.join("history.ron")),
+            session_path: cache_dir.join("session.ron"),
+            session_last_save: Instant::now(),
+
+            cache_dir
+        };
 
-        (worker_task_tx, worker_result_rx, state_rx, state_rx_2, control_tx)
+        (worker, worker_task_tx, worker_result_rx, state_rx, control_tx)
     }
 
-    async fn process_events(&mut self) {
+    async fn process_events(&mut self, mut shutdown_rx: ShutdownRx) {
         let mut rng = WyRand::new();
-        let mut player_events = None;
 
         loop {
-            if let Ok(task) = self.worker_task_rx.try_recv() {
-                match task {
-                    WorkerTask::Login(data) => {
-                        let mut token = None;
-
-                        if let Ok((t, rx)) = self.login_task(data).await {
-                            token = Some(t);
-                            player_events = Some(rx);
-                        }
-                        else {
-                            // TODO: Pass the error to the UI and show to user.
-                        }
-                        
-                        self.worker_result_tx.send(WorkerResult::Login(token)).unwrap();
-                    }
-                    WorkerTask::GetUserPlaylists => {
-                        if let Ok(result) = self.fetch_user_playlists_task().await {
-                            // TODO: Pass the error to the UI and show to user.
-                            self.worker_result_tx.send(WorkerResult::UserPlaylists(result)).unwrap();
-                        }
-                    }
-                    WorkerTask::GetFeaturedPlaylists => {
-                        if let Ok(result) = self.fetch_featured_playlists_task().await {
-                            // TODO: Pass the error to the UI and show to user.
-                            self.worker_result_tx.send(WorkerResult::FeaturedPlaylists(result)).unwrap();
-                        }
-                    }
-                    WorkerTask::GetPlaylistTracksInfo(playlist) => {
-                        if self.fetch_playlist_tracks_info_task(playlist).await.is_err() {
-                            // TODO: Pass the error to the UI and show to user.
-                        }
-                    }
-                    WorkerTask::GetRecommendationsForPlaylist(playlist) => {
-                        if let Ok(result) = self.get_recommendations_task(playlist, &mut rng).await {
-                            // TODO: Pass the error to the UI and show to user.
-                            self.worker_result_tx.send(WorkerResult::PlaylistRecommendations(result)).unwrap();
-                        }
-                    }
-                    WorkerTask::Search(query, search_type) => {
-                        if let Ok(result) = self.search(query, search_type).await {
-                            // TODO: Pass the error to the UI and show to user.
-                            self.worker_result_tx.send(WorkerResult::SearchResult(result)).unwrap();
-                        }
-                    }
-                    WorkerTask::AddTrackToPlaylist(track, playlist) => {
-                        if self.add_track_to_playlist_task(track, playlist).await.is_err() {
-                            // TODO: Pass the error to the UI and show to user.
-                        }
+            let next_position_update = tokio::time::Instant::from_std(self.player_position_last_update + POSITION_UPDATE_INTERVAL);
+
+            tokio::select! {
+                // Also fires if the UI went away without saying goodbye.
+                _ = &mut shutdown_rx => {
+                    self.save_session();
+
+                    if let Some(player) = self.spotify_player.as_ref() {
+                        player.stop();
                     }
-                    WorkerTask::RemoveTrackFromPlaylist(track, playlist) => {
-                        if self.remove_track_from_playlist_task(track, playlist).await.is_err() {
-                            // TODO: Pass the error to the UI and show to user.
-                        }
+
+                    break;
+                }
+                Some(request) = self.worker_task_rx.recv() => {
+                    match request {
+                        WorkerRequest::Task(id, task) => self.handle_task(id, task),
+                        WorkerRequest::Cancel(id) => self.cancel_task(id)
                     }
                 }
+                Some(done) = self.task_done_rx.recv() => {
+                    self.handle_task_done(done, &mut rng);
+                }
+                Some(control) = self.control_rx.recv() => {
+                    self.handle_control(control, &mut rng);
+                }
+                Some(event) = next_player_event(&mut self.player_events) => {
+                    self.handle_player_event(event);
+                }
+                _ = tokio::time::sleep_until(next_position_update), if !self.player_paused => {
+                    self.update_position();
+                }
             }
+        }
+    }
 
-            if let Ok(control) = self.control_rx.try_recv() {
-                match control {
-                    PlayerControl::Play => {
-                        if let Some(player) = self.spotify_player.as_ref() {
-                            player.play();
-                            self.state_tx.send(PlayerStateUpdate::Resumed).unwrap();
-                        }
-                    }
-                    PlayerControl::Pause => {
-                        if let Some(player) = self.spotify_player.as_ref() {
-                            player.pause();
-                            self.state_tx.send(PlayerStateUpdate::Paused).unwrap();
-                        }
-                    }
-                    PlayerControl::Stop => {
-                        if let Some(player) = self.spotify_player.as_ref() {
-                            player.stop();
-                            self.state_tx.send(PlayerStateUpdate::Stopped).unwrap();
-                        }
-                    }
-                    PlayerControl::PlayPause => {
-                        if let Some(player) = self.spotify_player.as_ref() {
-                            if self.player_paused {
-                                player.play();
-                                self.state_tx.send(PlayerStateUpdate::Resumed).unwrap();
-                            }
-                            else {
-                                player.pause();
-                                self.state_tx.send(PlayerStateUpdate::Paused).unwrap();
-                            }
-                        }
-                    }
-                    PlayerControl::StartPlaylist(mut tracks) => {
-                        rng.shuffle(&mut tracks);
-                        
-                        if self.start_playlist_task(tracks).is_err() {
-                            // TODO: Pass the error to the UI and show to user.
-                        }
-                    }
-                    PlayerControl::StartPlaylistAtTrack(mut tracks, start) => {
-                        let mut idx = 0;
-                        rng.shuffle(&mut tracks);
+    fn handle_task(&mut self, id: RequestId, task: WorkerTask) {
+        let kind = task.kind();
 
-                        if let Some((i, _)) = tracks.iter().enumerate().find(| (_, track) | track.id == start.id) {
-                            idx = i;
-                        } 
+        match task {
+            // Connecting can take a while, and the OAuth callback even longer, so it runs on its own too.
+            WorkerTask::Login(data) => {
+                let cache_dir = self.cache_dir.clone();
 
-                        if self.start_playlist_at_idx_task(tracks, idx).is_err() {
-                            // TODO: Pass the error to the UI and show to user.
-                        }
-                    }
-                    PlayerControl::NextTrack => {
-                        self.next_track();
-                    }
-                    PlayerControl::PreviousTrack => {
-                        self.previous_track();
-                    }
+                self.spawn_task(id, async move {
+                    TaskDone::LoggedIn(id, SpotifyWorker::connect_task(*data, cache_dir).await)
+                });
+            }
+            WorkerTask::GetRecentlyPlayed => {
+                let tracks = self.player_history.recent(50);
+                self.send_task_result(Some(id), kind, Ok(Some(WorkerResult::RecentlyPlayed(tracks))));
+            }
+            // Everything else only needs the context, so let it run alongside other tasks.
+            task => {
+                let context = self.context.clone();
+
+                self.spawn_task(id, async move {
+                    TaskDone::Finished(id, kind, context.run(task).await)
+                });
+            }
+        }
+    }
+
+    fn spawn_task(&mut self, id: RequestId, task: impl std::future::Future<Output = TaskDone> + Send + 'static) {
+        let task_done_tx = self.task_done_tx.clone();
+
+        let handle = tokio::spawn(async move {
+            let _ = task_done_tx.send(task.await);
+        });
+
+        self.running_tasks.insert(id, handle);
+    }
+
+    // Starts fetching the tracks for a new queue, dropping the one still being fetched.
+    fn spawn_queue_fetch(&mut self, playlist: Option<String>, fetch: impl std::future::Future<Output = Result<Vec<TrackInfo>>> + Send + 'static) {
+        if let Some(id) = self.queue_request.take() {
+            self.cancel_task(id);
+        }
+
+        let id = RequestId::next();
+        self.queue_request = Some(id);
+
+        self.spawn_task(id, async move {
+            TaskDone::QueueFetched(id, playlist, fetch.await)
+        });
+    }
+
+    fn handle_task_done(&mut self, done: TaskDone, rng: &mut WyRand) {
+        let id = match &done {
+            TaskDone::Finished(id, ..)
+            | TaskDone::LoggedIn(id, _)
+            | TaskDone::QueueFetched(id, ..)
+            | TaskDone::TrackFetched(id, ..) => *id
+        };
+
+        // Cancelled tasks may have finished before being aborted, nobody wants their results.
+        if self.running_tasks.remove(&id).is_none() {
+            return;
+        }
+
+        match done {
+            TaskDone::Finished(id, kind, result) => {
+                self.send_task_result(Some(id), kind, result);
+            }
+            TaskDone::LoggedIn(id, result) => {
+                let result = result
+                    .and_then(| login | self.finish_login(login))
+                    .map(| token | Some(WorkerResult::Login(token)))
+                ;
+
+                let logged_in = result.is_ok();
+                self.send_task_result(Some(id), TaskKind::Login, result);
+
+                if logged_in {
+                    self.restore_session();
                 }
             }
+            TaskDone::QueueFetched(_, playlist, result) => {
+                self.queue_request = None;
 
-            if let Some(events_rx) = player_events.as_mut() {
-                if let Ok(event) = events_rx.try_recv() {
-                    match event {
-                        PlayerEvent::Paused { .. } => {
-                            self.player_paused = true;
-                        }
-                        PlayerEvent::Playing { .. } | PlayerEvent::Started { .. } => {
-                            self.player_paused = false;
-                        }
-                        PlayerEvent::TimeToPreloadNextTrack { .. } => {
-                            if !self.player_tracks_queue.is_empty() {
-                                let target = {
-                                    if self.player_current_track + 1 >= self.player_tracks_queue.len() {
-                                        0
-                                    }
-                                    else {
-                                        self.player_current_track + 1
-                                    }
-                                };
-
-                                let track = &self.player_tracks_queue[target];
-
-                                if let Ok(track_id) = SpotifyId::from_uri(&track.id) {
-                                    if let Some(player) = self.spotify_player.as_ref() {
-                                        player.preload(track_id)
-                                    }
-                                }
-                            }
-                        }
-                        PlayerEvent::EndOfTrack { .. } => {
-                            self.next_track();
-                        }
-                        _ => {}
-                    }
+                match result.and_then(| tracks | self.start_queue(tracks, None, rng)) {
+                    Ok(()) => self.send_state(PlayerStateUpdate::ActivePlaylist(playlist)),
+                    Err(e) => self.send_result(None, WorkerResult::Error(TaskKind::StartPlaylist, e))
+                }
+            }
+            TaskDone::TrackFetched(_, idx, play, result) => {
+                match result {
+                    Ok(track) => self.add_track_to_queue(track, idx, play),
+                    Err(e) => self.send_result(None, WorkerResult::Error(TaskKind::AddTrackToQueue, e))
                 }
             }
+        }
+    }
 
-            std::thread::sleep(std::time::Duration::from_millis(10));
+    fn cancel_task(&mut self, id: RequestId) {
+        if let Some(handle) = self.running_tasks.remove(&id) {
+            handle.abort();
         }
     }
 
-    async fn login_task(&mut self, data: LoginData) -> Result<(Token, mpsc::UnboundedReceiver<PlayerEvent>)> {
-        let session_cfg = SessionConfig::default();
-        let session_creds = Credentials::with_password(data.username, data.password);
+    fn send_task_result(&self, id: Option<RequestId>, kind: TaskKind, result: Result<Option<WorkerResult>>) {
+        match result {
+            Ok(Some(result)) => self.send_result(id, result),
+            Ok(None) => {}
+            Err(e) => self.send_result(id, WorkerResult::Error(kind, e))
+        }
+    }
 
-        let mut api_client = {
-            let api_creds = rspotify::Credentials::from_env().ok_or(error::APILoginError::Credentials)?;
-            let api_cfg = rspotify::Config {
-                token_cached: false,
-                token_refreshing: true,
-                ..Default::default()
-            };
+    fn handle_control(&mut self, control: PlayerControl, rng: &mut WyRand) {
+        match control {
+            PlayerControl::Play => {
+                if let Some(player) = self.spotify_player.as_ref() {
+                    player.play();
+                    self.send_state(PlayerStateUpdate::Resumed);
+                }
+            }
+            PlayerControl::Pause => {
+                if let Some(player) = self.spotify_player.as_ref() {
+                    player.pause();
+                    self.send_state(PlayerStateUpdate::Paused);
+                }
+            }
+            PlayerControl::Stop => {
+                self.stop();
+            }
+            PlayerControl::PlayPause => {
+                if let Some(player) = self.spotify_player.as_ref() {
+                    if self.player_paused {
+                        player.play();
+                        self.send_state(PlayerStateUpdate::Resumed);
+                    }
+                    else {
+                        player.pause();
+                        self.send_state(PlayerStateUpdate::Paused);
+                    }
+                }
+            }
+            PlayerControl::StartPlaylist(tracks) => {
+                self.send_state(PlayerStateUpdate::ActivePlaylist(None));
 
-            let scopes = rspotify::scopes!(
-                "playlist-read-private",
-                "playlist-modify-public",
-                "playlist-modify-private"
-            );
+                if let Err(e) = self.start_queue(tracks, None, rng) {
+                    self.send_result(None, WorkerResult::Error(TaskKind::StartPlaylist, e));
+                }
+            }
+            PlayerControl::StartPlaylistAtTrack(tracks, start) => {
+                let start_idx = tracks.iter().position(| track | track.id == start.id);
+                self.send_state(PlayerStateUpdate::ActivePlaylist(None));
 
-            let api_oauth = rspotify::OAuth::from_env(scopes).ok_or(error::APILoginError::OAuth)?;
+                if let Err(e) = self.start_queue(tracks, start_idx, rng) {
+                    self.send_result(None, WorkerResult::Error(TaskKind::StartPlaylist, e));
+                }
+            }
+            PlayerControl::NextTrack => {
+                self.next_track(false);
+            }
+            PlayerControl::PreviousTrack => {
+                self.previous_track();
+            }
+            PlayerControl::Seek(position_ms) => {
+                self.seek(position_ms);
+            }
+            PlayerControl::SeekRelative(offset_ms) => {
+                let target = self.current_position_ms() as i64 + offset_ms;
+                let duration = self.player_queue
+                    .current()
+                    .map(| t | t.duration_ms as i64)
+                    .unwrap_or_default()
+                ;
+
+                // Seeking past the end of the track behaves like skipping to the next one.
+                if target >= duration {
+                    self.next_track(false);
+                }
+                else {
+                    self.seek(target.max(0) as u32);
+                }
+            }
+            PlayerControl::SetVolume(volume) => {
+                // Keep it around even without a mixer, it gets applied once one is created.
+                self.player_volume = volume;
 
-            AuthCodeSpotify::with_config(api_creds, api_oauth, api_cfg)
-        };
+                if let Some(mixer) = self.spotify_mixer.as_ref() {
+                    mixer.set_volume(volume);
+                }
 
-        if let Some(saved_token) = data.api_token {
-            if let Ok(mut token_lock) = api_client.token.lock().await {
-                *token_lock = Some(saved_token);
+                self.send_state(PlayerStateUpdate::Volume(volume));
             }
-            else {
-                return Err(Box::new(error::APILoginError::Token));
+            PlayerControl::SetAudioSettings(settings) => {
+                // Like the volume, these get applied once there's a session to create a player with.
+                self.player_audio_settings = settings;
+
+                if let Err(e) = self.recreate_player() {
+                    self.send_result(None, WorkerResult::Error(TaskKind::ApplyAudioSettings, e));
+                }
+            }
+            PlayerControl::SetDataSaver(enabled) => {
+                if self.player_data_saver != enabled {
+                    self.player_data_saver = enabled;
+
+                    if let Err(e) = self.recreate_player() {
+                        self.send_result(None, WorkerResult::Error(TaskKind::ApplyAudioSettings, e));
+                    }
+                }
             }
+            PlayerControl::SetShuffle(shuffle) => {
+                self.player_queue.set_shuffle(shuffle, rng);
 
-            self.api_client = Some(api_client.clone());
+                if !self.player_queue.is_empty() {
+                    self.send_queue_update();
+                }
+
+                self.send_state(PlayerStateUpdate::Shuffle(shuffle));
+            }
+            PlayerControl::SetRepeat(repeat) => {
+                self.player_queue.set_repeat(repeat);
+                self.send_state(PlayerStateUpdate::Repeat(repeat));
+            }
+            PlayerControl::GoToTrack(idx) => {
+                if self.player_queue.go_to(idx).is_ok() {
+                    self.load_current_track();
+                }
+            }
+            PlayerControl::RemoveTrack(idx) => {
+                self.remove_track_from_queue(idx);
+            }
+            PlayerControl::AddTrack(uri, idx, play) => {
+                let context = self.context.clone();
+                let id = RequestId::next();
+
+                self.spawn_task(id, async move {
+                    TaskDone::TrackFetched(id, idx, play, context.fetch_track_info_task(uri).await)
+                });
+            }
+            PlayerControl::PlayNext(track) => {
+                let was_empty = self.player_queue.is_empty();
+                self.player_queue.insert_next(track);
+                self.queue_changed(was_empty);
+            }
+            PlayerControl::AddToQueue(track) => {
+                let was_empty = self.player_queue.is_empty();
+                self.player_queue.push(track);
+                self.queue_changed(was_empty);
+            }
+            PlayerControl::MoveTrack(from, to) => {
+                if self.player_queue.move_track(from, to).is_ok() {
+                    self.send_queue_update();
+                }
+            }
+            PlayerControl::ClearQueue => {
+                self.player_queue.clear();
+                self.send_queue_update();
+            }
+            PlayerControl::StartPlaylistById(id) => {
+                let context = self.context.clone();
+                let playlist = id.clone();
+
+                self.spawn_queue_fetch(Some(playlist), async move {
+                    context.fetch_playlist_queue_task(&id).await
+                });
+            }
+            PlayerControl::PlayUri(uri) => {
+                let context = self.context.clone();
+                let playlist = match &uri {
+                    PlayableUri::Playlist(id) => Some(id.clone()),
+                    _ => None
+                };
+
+                self.spawn_queue_fetch(playlist, async move {
+                    context.fetch_uri_tracks_task(uri).await
+                });
+            }
         }
-        else {
-            let url = api_client.get_authorize_url(false).unwrap_or_default();
-        
-            if !url.is_empty() {
-                let server = Server::http("0.0.0.0:8888").unwrap();
-                let mut code = String::new();
-    
-                webbrowser::open(&url)?;
-    
-                for request in server.incoming_requests() {
-                    if request.url().contains("callback") {
-                        let uri_split = request.url().split('=').collect::<Vec<&str>>()[1];
-                        let uri_split = uri_split.split('&').collect::<Vec<&str>>()[0];
-    
-                        code = uri_split.to_string();
-                        break;
+    }
+
+    fn handle_player_event(&mut self, event: PlayerEvent) {
+        match event {
+            PlayerEvent::Paused { position_ms, .. } => {
+                self.player_paused = true;
+                self.set_position(position_ms);
+            }
+            PlayerEvent::Playing { position_ms, .. } | PlayerEvent::Started { position_ms, .. } => {
+                self.player_paused = false;
+                self.set_position(position_ms);
+            }
+            PlayerEvent::TimeToPreloadNextTrack { .. } => {
+                if let Some(track) = self.peek_next_track() {
+                    if let Ok(track_id) = SpotifyId::from_uri(&track.id) {
+                        if let Some(player) = self.spotify_player.as_ref() {
+                            player.preload(track_id)
+                        }
                     }
                 }
-                
-                api_client.request_token(&code).await?;
-                self.api_client = Some(api_client.clone());
             }
-            else {
-                return Err(Box::new(error::APILoginError::Token));
+            PlayerEvent::EndOfTrack { .. } => {
+                self.next_track(true);
             }
+            _ => {}
         }
+    }
 
-        let player_cfg = config::PlayerConfig {
-            gapless: true,
-            normalisation_type: config::NormalisationType::Auto,
-            normalisation_method: config::NormalisationMethod::Dynamic,
-            ..Default::default()
-        };
+    async fn connect_task(data: LoginData, cache_dir: PathBuf) -> Result<LoginSession> {
+        let session_cfg = SessionConfig::default();
+        let session_creds = Credentials::with_password(data.username, data.password);
 
         let cache = {
-            let cache_dir = dirs::cache_dir().unwrap().join("espot-rs");
             let system_location = Some(cache_dir.join("system"));
             let audio_location = Some(cache_dir.join("audio"));
             
@@ -406,237 +894,341 @@ impl SpotifyWorker {
         
         let session = Session::connect(session_cfg, session_creds, cache).await?;
 
-        let (player, rx) = Player::new(player_cfg, session.clone(), None, move || {
-            librespot::playback::audio_backend::find(None).unwrap()(None, config::AudioFormat::default())
-        });
+        let api_creds = data.oauth.credentials().ok_or(error::APILoginError::Credentials)?;
+        let api_cfg = rspotify::Config {
+            token_cached: false,
+            token_refreshing: true,
+            ..Default::default()
+        };
 
-        self.spotify_player = Some(player);
-        self.spotify_session = Some(session);
+        let scopes = rspotify::scopes!(
+            "playlist-read-private",
+            "playlist-modify-public",
+            "playlist-modify-private"
+        );
 
-        let token_lock = api_client.token.lock().await.unwrap();
-    
-        Ok((token_lock.clone().unwrap(), rx))
-    }
+        let api_oauth = data.oauth.oauth(scopes);
 
-    async fn fetch_user_playlists_task(&mut self) -> Result<Vec<(String, Playlist)>> {        
-        let client = self.api_client.as_ref().ok_or(error::WorkerError::NoAPIClient)?;
-        let playlists = client.current_user_playlists_manual(None, None).await?;
+        // Without a client secret, PKCE is the only flow Spotify allows.
+        let (token, api): (Token, Arc<dyn ApiBackend>) = if api_creds.secret.is_some() {
+            let mut client = AuthCodeSpotify::with_config(api_creds, api_oauth, api_cfg);
+            let token = auth::authorize(&mut client, data.api_token, | client | client.get_authorize_url(false)).await?;
 
-        self.process_playlist_info(playlists.items).await
-    }
+            (token, Arc::new(WebApi { client, session: session.clone() }))
+        }
+        else {
+            let mut client = AuthCodePkceSpotify::with_config(api_creds, api_oauth, api_cfg);
+            let token = auth::authorize(&mut client, data.api_token, | client | client.get_authorize_url(None)).await?;
 
-    async fn fetch_featured_playlists_task(&mut self) -> Result<Vec<(String, Playlist)>> {
-        let client = self.api_client.as_ref().ok_or(error::WorkerError::NoAPIClient)?;
-        let featured = client.featured_playlists(None, None, None, Some(5), None).await?;
+            (token, Arc::new(WebApi { client, session: session.clone() }))
+        };
 
-        self.process_playlist_info(featured.playlists.items).await
+        Ok(LoginSession { session, api, token })
     }
 
-    async fn fetch_playlist_tracks_info_task(&mut self, playlist: Playlist) -> Result<()> {
-        let track_ids = playlist.tracks.into_iter().map(|t| t.to_uri()).collect();
-        let tracks = self.make_track_info_vec(track_ids).await?;
-
-        self.worker_result_tx.send(WorkerResult::PlaylistTrackInfo(tracks)).unwrap();
-        Ok(())
-    }
+    // Sets up playback for a session that just logged in.
+    fn finish_login(&mut self, login: LoginSession) -> Result<Token> {
+        let mixer = mixer::find(None).ok_or(error::WorkerError::NoMixer)?(MixerConfig::default());
+        mixer.set_volume(self.player_volume);
+        self.spotify_mixer = Some(mixer);
 
-    async fn search(&mut self, query: String, search_type: SearchType) -> Result<SearchResult> {
-        let client = self.api_client.as_ref().ok_or(error::WorkerError::NoAPIClient)?;
-        Ok(client.search(&query, &search_type, None, None, None, None).await?)
-    }
+        let (player, rx) = self.create_player(login.session.clone())?;
+        self.send_state(PlayerStateUpdate::Bitrate(self.bitrate()));
 
-    async fn get_recommendations_task(&mut self, playlist: Playlist, rng: &mut WyRand) -> Result<Vec<TrackInfo>> {
-        let client = self.api_client.as_ref().ok_or(error::WorkerError::NoAPIClient)?;
+        self.spotify_session = Some(login.session);
+        self.spotify_player = Some(Box::new(player));
+        self.player_events = Some(rx);
+        self.context.api = Some(login.api);
 
-        let mut playlist_tracks: Vec<TrackId> = playlist.tracks
-            .into_iter()
-            .filter_map(| id | {
-                TrackId::from_uri(&id.to_uri()).ok()
-            })
-            .collect()
-        ;
+        Ok(login.token)
+    }
 
-        // The max amount of tracks for seeding you can use is 5, so shuffle them around
-        // and then grab the first 5 elements for our recommendation adventures.
-        rng.shuffle(&mut playlist_tracks);
-        playlist_tracks.truncate(5);
+    fn create_player(&self, session: Session) -> Result<(Player, mpsc::UnboundedReceiver<PlayerEvent>)> {
+        let mixer = self.spotify_mixer.as_ref().ok_or(error::WorkerError::NoMixer)?;
 
-        let seed_artists: Option<&Vec<ArtistId>> = None;
-        let seed_genres: Option<Vec<&str>> = None;
+        let player_cfg = self.player_audio_settings.player_config(self.bitrate());
 
-        let results = client.recommendations(
-            None,
-            seed_artists,
-            seed_genres,
-            Some(&playlist_tracks),
-            None,
-            Some(50)
-        ).await.unwrap();
+        // Looked up here so a bad backend name is an error, instead of a panic on the player's thread.
+        let backend = self.player_audio_settings.sink_builder().ok_or(error::WorkerError::NoAudioBackend)?;
+        let device = self.player_audio_settings.device.clone();
+        let format = self.player_audio_settings.format.into();
 
-        let tracks = results.tracks
-            .into_iter()
-            .filter_map(| t | t.id)
-            .map(| id | id.uri())
-            .collect()
-        ;
+        Ok(Player::new(player_cfg, session, mixer.get_audio_filter(), move || backend(device, format)))
+    }
 
-        self.make_track_info_vec(tracks).await
+    fn bitrate(&self) -> Bitrate {
+        if self.player_data_saver {
+            Bitrate::Kbps96
+        }
+        else {
+            self.player_audio_settings.bitrate
+        }
     }
 
-    async fn get_tracks_info(&mut self, tracks: &[TrackId]) -> Result<Vec<TrackInfo>> {
-        let client = self.api_client.as_ref().ok_or(error::WorkerError::NoAPIClient)?;
+    // Swaps the player for one using the current audio settings, picking up where the old one was.
+    fn recreate_player(&mut self) -> Result<()> {
+        let session = match self.spotify_session.clone() {
+            Some(session) => session,
+            None => return Ok(())
+        };
+
+        let position_ms = self.current_position_ms();
+        let (player, rx) = self.create_player(session)?;
 
-        let mut cache_dirty = false;
-        let mut result = Vec::with_capacity(tracks.len());
+        if let Some(old_player) = self.spotify_player.take() {
+            old_player.stop();
+        }
 
-        // The tracks endpoint accepts a maximum of 50 tracks at a time.
-        for tracks_batch in tracks.chunks(50) {
-            let api_response = client.tracks(&tracks_batch.to_vec(), None).await?;
+        let mut player: Box<dyn PlayerBackend> = Box::new(player);
 
-            for track in api_response {
-                if let Some(track) = self.api_cache_handler.cache_track_info(track) {
-                    cache_dirty = true;
-                    result.push(track.clone());
-                }
+        if let Some(track) = self.player_queue.current() {
+            if let Ok(track_id) = SpotifyId::from_uri(&track.id) {
+                player.load(track_id, !self.player_paused, position_ms);
             }
         }
 
-        if cache_dirty {
-            self.api_cache_handler.save_cache().await;
-        }
+        self.spotify_player = Some(player);
+        self.player_events = Some(rx);
+
+        self.send_state(PlayerStateUpdate::Bitrate(self.bitrate()));
+        self.set_position(position_ms);
 
-        Ok(result)
+        Ok(())
     }
 
-    fn start_playlist_task(&mut self, tracks: Vec<TrackInfo>) -> Result<()> {
-        let player = self.spotify_player.as_mut().ok_or(error::WorkerError::NoSpotifyPlayer)?;
-        let track = tracks[0].clone();
-        let track_id = SpotifyId::from_uri(&track.id).map_err(|_| error::WorkerError::BadSpotifyId)?;
+    fn start_queue(&mut self, tracks: Vec<TrackInfo>, start: Option<usize>, rng: &mut WyRand) -> Result<()> {
+        if self.spotify_player.is_none() {
+            return Err(error::WorkerError::NoSpotifyPlayer.into());
+        }
 
-        player.load(track_id, true, 0);
+        let track = self.player_queue.start(tracks, start, rng)?;
+        SpotifyId::from_uri(&track.id).map_err(|_| error::WorkerError::BadSpotifyId)?;
 
-        self.player_current_track = 0;
-        self.player_tracks_queue = tracks;
-        self.state_tx.send(PlayerStateUpdate::EndOfTrack(track)).unwrap();
+        self.load_current_track();
 
         Ok(())
     }
 
-    fn start_playlist_at_idx_task(&mut self, tracks: Vec<TrackInfo>, idx: usize) -> Result<()> {
-        let player = self.spotify_player.as_mut().ok_or(error::WorkerError::NoSpotifyPlayer)?;
-        let track = tracks[idx].clone();
-        let track_id = SpotifyId::from_uri(&track.id).map_err(|_| error::WorkerError::BadSpotifyId)?;
+    fn add_track_to_queue(&mut self, track: TrackInfo, idx: usize, play: bool) {
+        self.player_queue.insert(idx, track, play);
+
+        if play {
+            self.load_current_track();
+        }
+        else {
+            self.send_queue_update();
+        }
+    }
+
+    // Adding to an empty queue starts playing what was added.
+    fn queue_changed(&mut self, was_empty: bool) {
+        if was_empty {
+            self.send_state(PlayerStateUpdate::ActivePlaylist(None));
+            self.load_current_track();
+        }
+        else {
+            self.send_queue_update();
+        }
+    }
+
+    fn remove_track_from_queue(&mut self, idx: usize) {
+        match self.player_queue.remove(idx) {
+            Ok(true) if !self.player_queue.is_empty() => self.load_current_track(),
+            Ok(_) => self.send_queue_update(),
+            Err(_) => {}
+        }
+    }
 
-        player.load(track_id, true, 0);
+    fn next_track(&mut self, track_ended: bool) {
+        // After going back through the history, moving forward retraces it, unless the track's on repeat.
+        let repeat_track = track_ended && self.player_queue.repeat() == RepeatMode::Track;
 
-        self.player_current_track = idx;
-        self.player_tracks_queue = tracks;
-        self.state_tx.send(PlayerStateUpdate::EndOfTrack(track)).unwrap();
+        if !repeat_track {
+            if let Some(track) = self.player_history.next().cloned() {
+                self.play_history_track(track);
+                return;
+            }
+        }
 
-        Ok(())
+        match self.player_queue.next(track_ended) {
+            Ok(Some(_)) => self.load_current_track(),
+            // Either the end of the queue was reached, or there's nothing in it.
+            Ok(None) | Err(_) => self.stop()
+        }
     }
 
-    async fn add_track_to_playlist_task(&mut self, track: String, playlist: String) -> Result<()> {
-        let api_client = self.api_client.as_mut().ok_or(error::WorkerError::NoAPIClient)?;
-        let track_id = TrackId::from_uri(&track).map_err(|_| error::WorkerError::BadSpotifyId)?;
-        let playlist_id = PlaylistId::from_uri(&playlist).map_err(|_| error::WorkerError::BadSpotifyId)?;
+    // The track next_track will play once the current one ends.
+    fn peek_next_track(&self) -> Option<&TrackInfo> {
+        if self.player_queue.repeat() != RepeatMode::Track {
+            if let Some(track) = self.player_history.peek_next() {
+                return Some(track);
+            }
+        }
 
-        let items: Vec<&dyn PlayableId> = vec![&track_id];
+        self.player_queue.peek_next()
+    }
 
-        api_client.playlist_add_items(&playlist_id, items, None).await.map(|_| Ok(()))?
+    fn previous_track(&mut self) {
+        if let Some(track) = self.player_history.previous().cloned() {
+            self.play_history_track(track);
+        }
+        // Nothing older in the history, so fall back to the queue's order.
+        else if self.player_queue.previous().is_ok() {
+            self.load_current_track();
+        }
     }
 
-    async fn remove_track_from_playlist_task(&mut self, track: String, playlist: String) -> Result<()> {
-        let api_client = self.api_client.as_mut().ok_or(error::WorkerError::NoAPIClient)?;
-        let playlist_id = PlaylistId::from_uri(&playlist).map_err(|_| error::WorkerError::BadSpotifyId)?;
-        let track_id = TrackId::from_uri(&track).map_err(|_| error::WorkerError::BadSpotifyId)?;
-        let track_ids: Vec<&dyn PlayableId> = vec![&track_id];
-        
-        api_client.playlist_remove_all_occurrences_of_items(&playlist_id, track_ids, None).await.map(|_| Ok(()))?
+    // Plays a track from the history without recording it again.
+    fn play_history_track(&mut self, track: TrackInfo) {
+        self.player_queue.set_current(track);
+        self.play_current_track();
     }
 
-    async fn process_playlist_info(&mut self, playlists: Vec<SimplifiedPlaylist>) -> Result<Vec<(String, Playlist)>> {
-        let session = self.spotify_session.as_ref().ok_or(error::WorkerError::NoSpotifySession)?;
-        let mut result = Vec::new();
+    fn stop(&mut self) {
+        if let Some(player) = self.spotify_player.as_ref() {
+            player.stop();
 
-        for playlist in playlists {
-            let playlist_uri = playlist.id.uri();
-            let playlist_id = SpotifyId::from_uri(&playlist_uri).map_err(|_| error::WorkerError::BadSpotifyId)?;
+            self.player_paused = true;
+            self.position_tx.send_replace(None);
+            self.send_state(PlayerStateUpdate::Stopped);
+        }
+    }
 
-            let images: Vec<(u32, String)> = playlist.images
-                .iter()
-                .map(| i | {
-                    (i.width.unwrap_or_default(), i.url.clone())
-                })
-                .collect()
-            ;
+    fn load_current_track(&mut self) {
+        if let Some(track) = self.play_current_track() {
+            self.player_history.record(track);
+        }
+    }
 
-            self.api_cache_handler.cache_cover_image(&playlist_uri, &images).await;
+    // Returns the track that started playing, if any.
+    fn play_current_track(&mut self) -> Option<TrackInfo> {
+        let mut started = None;
 
-            if let Ok(p) = Playlist::get(session, playlist_id).await {
-                result.push((playlist.id.to_string(), p));
+        if let (Some(player), Some(track)) = (self.spotify_player.as_mut(), self.player_queue.current()) {
+            if let Ok(track_id) = SpotifyId::from_uri(&track.id) {
+                player.load(track_id, true, 0);
+                started = Some(track.clone());
             }
         }
 
-        Ok(result)
+        // The current track's index may have changed, even if the queue didn't.
+        self.send_queue_update();
+
+        if let Some(track) = started.as_ref() {
+            self.send_state(PlayerStateUpdate::EndOfTrack(track.clone()));
+        }
+
+        self.set_position(0);
+
+        if started.is_some() {
+            self.save_session();
+        }
+
+        started
     }
 
-    async fn make_track_info_vec(&mut self, tracks: Vec<String>) -> Result<Vec<TrackInfo>> {
-        let mut result = Vec::new();
+    fn save_session(&mut self) {
+        // Without a player nothing could've been played, and there might be a session left to restore.
+        if self.spotify_player.is_none() {
+            return;
+        }
 
-        let tracks_to_fetch: Vec<TrackId> = tracks.into_iter()
-            // Only fetch tracks with a valid Spotify ID.
-            .filter_map(| uri | {
-                TrackId::from_uri(&uri).ok()
-            })
-            // And filter out the tracks that we already have cached.
-            .filter(| track | {
-                if let Some(track) = self.api_cache_handler.get_track_info(&track.uri()) {
-                    result.push(track);
-                    false
-                }
-                else {
-                    true
-                }
-            })
-            .collect()
-        ;
+        let session = SavedSession {
+            queue: self.player_queue.clone(),
+            position_ms: self.current_position_ms()
+        };
+
+        session.save(&self.session_path);
+        self.session_last_save = Instant::now();
+    }
 
-        let mut fetched_tracks = self.get_tracks_info(&tracks_to_fetch).await?;
-        result.append(&mut fetched_tracks);
+    // Loads the track the last session was at, paused where it was left.
+    fn restore_session(&mut self) {
+        let session = match SavedSession::load(&self.session_path) {
+            Some(session) => session,
+            None => return
+        };
 
-        Ok(result)
+        let track = match session.queue.current() {
+            Some(track) => track.clone(),
+            None => return
+        };
+
+        if let (Some(player), Ok(track_id)) = (self.spotify_player.as_mut(), SpotifyId::from_uri(&track.id)) {
+            player.load(track_id, false, session.position_ms);
+
+            self.player_queue = session.queue;
+            self.player_paused = true;
+
+            self.send_state(PlayerStateUpdate::Shuffle(self.player_queue.shuffle()));
+            self.send_state(PlayerStateUpdate::Repeat(self.player_queue.repeat()));
+            self.send_queue_update();
+            self.send_state(PlayerStateUpdate::EndOfTrack(track));
+            self.send_state(PlayerStateUpdate::Paused);
+            self.set_position(session.position_ms);
+        }
     }
 
-    fn next_track(&mut self) {
-        self.player_current_track += 1;
+    fn seek(&mut self, position_ms: u32) {
+        if let Some(player) = self.spotify_player.as_ref() {
+            player.seek(position_ms);
 
-        if self.player_current_track >= self.player_tracks_queue.len() {
-            self.player_current_track = 0;
+            self.set_position(position_ms);
+            self.send_state(PlayerStateUpdate::Seeked(position_ms));
         }
+    }
 
-        self.load_current_track();
+    fn set_position(&mut self, position_ms: u32) {
+        self.player_position_ms = position_ms;
+        self.player_position_instant = Instant::now();
+
+        self.send_position_update();
     }
 
-    fn previous_track(&mut self) {
-        if self.player_current_track == 0 {
-            self.player_current_track = self.player_tracks_queue.len() - 1;
+    fn send_result(&self, id: Option<RequestId>, result: WorkerResult) {
+        self.worker_result_tx.send((id, result)).unwrap();
+        (self.context.repaint)();
+    }
+
+    fn send_state(&self, state: PlayerStateUpdate) {
+        self.context.send_state(state);
+    }
+
+    fn send_queue_update(&self) {
+        self.send_state(PlayerStateUpdate::Queue(self.player_queue.entries().to_vec(), self.player_queue.current_idx()));
+    }
+
+    // Runs every so often while playing.
+    fn update_position(&mut self) {
+        self.send_position_update();
+
+        if self.session_last_save.elapsed() >= SESSION_SAVE_INTERVAL {
+            self.save_session();
         }
-        else {
-            self.player_current_track -= 1;
+    }
+
+    fn send_position_update(&mut self) {
+        if let Some(track) = self.player_queue.current() {
+            let position = PlaybackPosition {
+                track_id: track.id.clone(),
+                position_ms: self.current_position_ms(),
+                timestamp: Instant::now(),
+                playing: !self.player_paused
+            };
+
+            self.position_tx.send_replace(Some(position));
+            (self.context.repaint)();
         }
 
-        self.load_current_track();
+        self.player_position_last_update = Instant::now();
     }
 
-    fn load_current_track(&mut self) {
-        if let Some(player) = self.spotify_player.as_mut() {
-            let track = &self.player_tracks_queue[self.player_current_track];
-
-            if let Ok(track_id) = SpotifyId::from_uri(&track.id) {
-                player.load(track_id, true, 0);
-                self.state_tx.send(PlayerStateUpdate::EndOfTrack(track.clone())).unwrap();
-            }
+    fn current_position_ms(&self) -> u32 {
+        if self.player_paused {
+            self.player_position_ms
+        }
+        else {
+            self.player_position_ms + self.player_position_instant.elapsed().as_millis() as u32
         }
     }
 }
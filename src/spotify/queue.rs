@@ -0,0 +1,654 @@
+use nanorand::{Rng, WyRand};
+use serde::{Deserialize, Serialize};
+
+use super::error::WorkerError;
+use super::{RepeatMode, TrackInfo};
+
+type Result<T> = std::result::Result<T, WorkerError>;
+
+
+// A track in the queue. The same track can be queued more than once, the ID tells them apart.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct QueueEntry {
+    pub id: u64,
+    pub track: TrackInfo
+}
+
+// The tracks being played, in playback order, and where we are in them.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PlayQueue {
+    shuffle: bool,
+    repeat: RepeatMode,
+
+    current: usize,
+    tracks: Vec<QueueEntry>,
+    // The tracks in the order they were given, to go back to once shuffle is turned off.
+    original: Vec<QueueEntry>,
+
+    next_entry_id: u64
+}
+
+impl PlayQueue {
+    pub fn entries(&self) -> &[QueueEntry] {
+        &self.tracks
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    pub fn current(&self) -> Option<&TrackInfo> {
+        self.tracks.get(self.current).map(| entry | &entry.track)
+    }
+
+    pub fn current_idx(&self) -> usize {
+        self.current
+    }
+
+    pub fn shuffle(&self) -> bool {
+        self.shuffle
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+    }
+
+    // Replaces the queue, starting at `start` if given. When shuffling, the starting
+    // track is moved to the front and everything else is shuffled.
+    pub fn start(&mut self, tracks: Vec<TrackInfo>, start: Option<usize>, rng: &mut WyRand) -> Result<&TrackInfo> {
+        if tracks.is_empty() {
+            return Err(WorkerError::EmptyQueue);
+        }
+
+        if let Some(idx) = start {
+            if idx >= tracks.len() {
+                return Err(WorkerError::BadQueueIndex);
+            }
+        }
+
+        let entries: Vec<QueueEntry> = tracks.into_iter().map(| track | self.make_entry(track)).collect();
+
+        self.original = entries.clone();
+        self.arrange(entries, start, rng);
+
+        Ok(&self.tracks[self.current].track)
+    }
+
+    // Only reorders the tracks after the current one, so playback isn't interrupted
+    // and the ones that already played don't come up again.
+    pub fn set_shuffle(&mut self, shuffle: bool, rng: &mut WyRand) {
+        self.shuffle = shuffle;
+
+        if self.tracks.is_empty() {
+            return;
+        }
+
+        let upcoming = self.current + 1;
+
+        if shuffle {
+            rng.shuffle(&mut self.tracks[upcoming..]);
+        }
+        else {
+            let mut remaining = self.tracks.split_off(upcoming);
+
+            // Put whatever is left back in the order it was given in.
+            for track in self.original.iter() {
+                if let Some(idx) = remaining.iter().position(| entry | entry.id == track.id) {
+                    self.tracks.push(remaining.remove(idx));
+                }
+            }
+
+            self.tracks.append(&mut remaining);
+            self.original = self.tracks.clone();
+        }
+    }
+
+    // The track that should play after the current one. `track_ended` is false when
+    // the user skips, in which case repeating the current track is ignored.
+    // Returns None once the end is reached without repeat.
+    pub fn next_idx(&self, track_ended: bool) -> Result<Option<usize>> {
+        let len = self.tracks.len();
+
+        if len == 0 {
+            Err(WorkerError::EmptyQueue)
+        }
+        else if track_ended && self.repeat == RepeatMode::Track {
+            Ok(Some(self.current))
+        }
+        else if self.current + 1 < len {
+            Ok(Some(self.current + 1))
+        }
+        else if self.repeat == RepeatMode::None {
+            Ok(None)
+        }
+        else {
+            Ok(Some(0))
+        }
+    }
+
+    pub fn previous_idx(&self) -> Result<usize> {
+        let len = self.tracks.len();
+
+        if len == 0 {
+            Err(WorkerError::EmptyQueue)
+        }
+        else if self.current > 0 {
+            Ok(self.current - 1)
+        }
+        else if self.repeat == RepeatMode::None {
+            // Nothing before the first track, so just start it over.
+            Ok(0)
+        }
+        else {
+            Ok(len - 1)
+        }
+    }
+
+    // The track to preload while the current one is playing.
+    pub fn peek_next(&self) -> Option<&TrackInfo> {
+        self.next_idx(true).ok().flatten().map(| idx | &self.tracks[idx].track)
+    }
+
+    // Moves to the next track. Returns None, and stays where it was, once the end is reached.
+    pub fn next(&mut self, track_ended: bool) -> Result<Option<&TrackInfo>> {
+        match self.next_idx(track_ended)? {
+            Some(idx) => {
+                self.current = idx;
+                Ok(Some(&self.tracks[idx].track))
+            }
+            None => Ok(None)
+        }
+    }
+
+    pub fn previous(&mut self) -> Result<&TrackInfo> {
+        self.current = self.previous_idx()?;
+        Ok(&self.tracks[self.current].track)
+    }
+
+    pub fn go_to(&mut self, idx: usize) -> Result<&TrackInfo> {
+        if self.tracks.is_empty() {
+            Err(WorkerError::EmptyQueue)
+        }
+        else if idx >= self.tracks.len() {
+            Err(WorkerError::BadQueueIndex)
+        }
+        else {
+            self.current = idx;
+            Ok(&self.tracks[idx].track)
+        }
+    }
+
+    // Inserts a track at `idx`, or at the end if it's past it. The current track
+    // stays the same unless `set_as_current` is true.
+    pub fn insert(&mut self, idx: usize, track: TrackInfo, set_as_current: bool) -> &TrackInfo {
+        let idx = idx.min(self.tracks.len());
+
+        if idx <= self.current && !self.tracks.is_empty() {
+            self.current += 1;
+        }
+
+        let entry = self.make_entry(track);
+        self.tracks.insert(idx, entry.clone());
+
+        // Without shuffle, the user's order is the one to go back to later.
+        if self.shuffle {
+            self.original.push(entry);
+        }
+        else {
+            self.original.insert(idx.min(self.original.len()), entry);
+        }
+
+        if set_as_current {
+            self.current = idx;
+        }
+
+        &self.tracks[idx].track
+    }
+
+    // Makes a track the current one, adding it in front of the current track if it
+    // isn't in the queue. Returns true if it was added.
+    pub fn set_current(&mut self, track: TrackInfo) -> bool {
+        match self.tracks.iter().position(| entry | entry.track.id == track.id) {
+            Some(idx) => {
+                self.current = idx;
+                false
+            }
+            None => {
+                self.insert(self.current, track, true);
+                true
+            }
+        }
+    }
+
+    // Queues a track to play right after the current one.
+    pub fn insert_next(&mut self, track: TrackInfo) {
+        let idx = if self.tracks.is_empty() { 0 } else { self.current + 1 };
+        self.insert(idx, track, false);
+    }
+
+    pub fn push(&mut self, track: TrackInfo) {
+        self.insert(self.tracks.len(), track, false);
+    }
+
+    // Moves a track from one position to another, the current track stays the same.
+    pub fn move_track(&mut self, from: usize, to: usize) -> Result<()> {
+        if self.tracks.is_empty() {
+            return Err(WorkerError::EmptyQueue);
+        }
+        else if from >= self.tracks.len() || to >= self.tracks.len() {
+            return Err(WorkerError::BadQueueIndex);
+        }
+
+        let track = self.tracks.remove(from);
+        self.tracks.insert(to, track);
+
+        if from == self.current {
+            self.current = to;
+        }
+        else if from < self.current && to >= self.current {
+            self.current -= 1;
+        }
+        else if from > self.current && to <= self.current {
+            self.current += 1;
+        }
+
+        // Without shuffle, the user's order is the one to go back to later.
+        if !self.shuffle {
+            self.original = self.tracks.clone();
+        }
+
+        Ok(())
+    }
+
+    // Drops everything but the current track.
+    pub fn clear(&mut self) {
+        self.tracks = self.tracks.get(self.current).cloned().into_iter().collect();
+        self.original = self.tracks.clone();
+        self.current = 0;
+    }
+
+    // Returns true if the current track was the one removed. Whatever came after it
+    // takes its place, or nothing if the queue is now empty.
+    pub fn remove(&mut self, idx: usize) -> Result<bool> {
+        if self.tracks.is_empty() {
+            return Err(WorkerError::EmptyQueue);
+        }
+        else if idx >= self.tracks.len() {
+            return Err(WorkerError::BadQueueIndex);
+        }
+
+        let entry = self.tracks.remove(idx);
+
+        if let Some(i) = self.original.iter().position(| e | e.id == entry.id) {
+            self.original.remove(i);
+        }
+
+        if idx < self.current {
+            self.current -= 1;
+            Ok(false)
+        }
+        else if idx == self.current {
+            if self.current >= self.tracks.len() {
+                self.current = 0;
+            }
+
+            Ok(true)
+        }
+        else {
+            Ok(false)
+        }
+    }
+
+    fn make_entry(&mut self, track: TrackInfo) -> QueueEntry {
+        let id = self.next_entry_id;
+        self.next_entry_id += 1;
+
+        QueueEntry { id, track }
+    }
+
+    fn arrange(&mut self, mut tracks: Vec<QueueEntry>, start: Option<usize>, rng: &mut WyRand) {
+        if self.shuffle {
+            match start {
+                Some(idx) if idx < tracks.len() => {
+                    tracks.swap(0, idx);
+                    rng.shuffle(&mut tracks[1..]);
+                }
+                _ => rng.shuffle(&mut tracks)
+            }
+
+            self.current = 0;
+        }
+        else {
+            self.current = start.unwrap_or_default();
+        }
+
+        self.tracks = tracks;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use super::super::fake::{test_track, test_tracks};
+
+    fn tracks(queue: &PlayQueue) -> Vec<TrackInfo> {
+        queue.entries().iter().map(| entry | entry.track.clone()).collect()
+    }
+
+    fn ids(tracks: &[TrackInfo]) -> Vec<String> {
+        tracks.iter().map(| t | t.id.clone()).collect()
+    }
+
+    fn started_queue(count: u32, start: Option<usize>, repeat: RepeatMode) -> PlayQueue {
+        let mut queue = PlayQueue::default();
+
+        queue.set_repeat(repeat);
+        queue.start(test_tracks(count), start, &mut WyRand::new_seed(0)).unwrap();
+
+        queue
+    }
+
+    #[test]
+    fn empty_queue_operations_fail() {
+        let mut queue = PlayQueue::default();
+        let mut rng = WyRand::new_seed(0);
+
+        assert!(matches!(queue.start(Vec::new(), None, &mut rng), Err(WorkerError::EmptyQueue)));
+        assert!(matches!(queue.next(false), Err(WorkerError::EmptyQueue)));
+        assert!(matches!(queue.next(true), Err(WorkerError::EmptyQueue)));
+        assert!(matches!(queue.previous(), Err(WorkerError::EmptyQueue)));
+        assert!(matches!(queue.go_to(0), Err(WorkerError::EmptyQueue)));
+        assert!(matches!(queue.remove(0), Err(WorkerError::EmptyQueue)));
+
+        assert!(queue.current().is_none());
+        assert!(queue.peek_next().is_none());
+
+        // Shuffling an empty queue only changes the setting.
+        queue.set_shuffle(true, &mut rng);
+        assert!(queue.shuffle);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn start_checks_the_starting_index() {
+        let mut queue = PlayQueue::default();
+        let mut rng = WyRand::new_seed(0);
+
+        assert!(matches!(queue.start(test_tracks(3), Some(3), &mut rng), Err(WorkerError::BadQueueIndex)));
+        assert!(queue.is_empty());
+
+        assert_eq!(queue.start(test_tracks(3), Some(2), &mut rng).unwrap().id, test_track(2).id);
+        assert_eq!(queue.current, 2);
+
+        assert_eq!(queue.start(test_tracks(3), None, &mut rng).unwrap().id, test_track(0).id);
+        assert_eq!(queue.current, 0);
+    }
+
+    #[test]
+    fn next_without_repeat_stops_at_the_end() {
+        let mut queue = started_queue(3, Some(1), RepeatMode::None);
+
+        assert_eq!(queue.next(false).unwrap().unwrap().id, test_track(2).id);
+        assert!(queue.next(false).unwrap().is_none());
+        assert!(queue.next(true).unwrap().is_none());
+        assert_eq!(queue.current, 2);
+        assert!(queue.peek_next().is_none());
+    }
+
+    #[test]
+    fn next_with_playlist_repeat_wraps_around() {
+        let mut queue = started_queue(3, Some(2), RepeatMode::Playlist);
+
+        assert_eq!(queue.peek_next().unwrap().id, test_track(0).id);
+        assert_eq!(queue.next(true).unwrap().unwrap().id, test_track(0).id);
+        assert_eq!(queue.next(false).unwrap().unwrap().id, test_track(1).id);
+    }
+
+    #[test]
+    fn track_repeat_only_applies_when_the_track_ends() {
+        let mut queue = started_queue(3, Some(1), RepeatMode::Track);
+
+        assert_eq!(queue.peek_next().unwrap().id, test_track(1).id);
+        assert_eq!(queue.next(true).unwrap().unwrap().id, test_track(1).id);
+        assert_eq!(queue.next(false).unwrap().unwrap().id, test_track(2).id);
+
+        // Skipping past the end wraps around, like repeating the playlist.
+        assert_eq!(queue.next(false).unwrap().unwrap().id, test_track(0).id);
+    }
+
+    #[test]
+    fn single_track_queue() {
+        let mut queue = started_queue(1, None, RepeatMode::None);
+        assert!(queue.next(false).unwrap().is_none());
+        assert_eq!(queue.previous().unwrap().id, test_track(0).id);
+
+        let mut queue = started_queue(1, None, RepeatMode::Playlist);
+        assert_eq!(queue.next(false).unwrap().unwrap().id, test_track(0).id);
+        assert_eq!(queue.previous().unwrap().id, test_track(0).id);
+    }
+
+    #[test]
+    fn previous_at_the_start() {
+        let mut queue = started_queue(3, None, RepeatMode::None);
+        assert_eq!(queue.previous().unwrap().id, test_track(0).id);
+
+        let mut queue = started_queue(3, None, RepeatMode::Playlist);
+        assert_eq!(queue.previous().unwrap().id, test_track(2).id);
+        assert_eq!(queue.previous().unwrap().id, test_track(1).id);
+    }
+
+    #[test]
+    fn go_to_checks_bounds() {
+        let mut queue = started_queue(3, None, RepeatMode::None);
+
+        assert_eq!(queue.go_to(2).unwrap().id, test_track(2).id);
+        assert!(matches!(queue.go_to(3), Err(WorkerError::BadQueueIndex)));
+        assert_eq!(queue.current, 2);
+    }
+
+    #[test]
+    fn shuffle_only_reorders_upcoming_tracks() {
+        let mut queue = started_queue(10, Some(4), RepeatMode::None);
+        let mut rng = WyRand::new_seed(1);
+
+        queue.set_shuffle(true, &mut rng);
+        assert_eq!(queue.current, 4);
+        assert_eq!(ids(&tracks(&queue)[..5]), ids(&test_tracks(5)));
+
+        let mut shuffled = ids(&tracks(&queue));
+        shuffled.sort();
+        assert_eq!(shuffled, ids(&test_tracks(10)));
+
+        // Whatever played while shuffled stays where it is, the rest goes back in order.
+        queue.go_to(7).unwrap();
+        let played = ids(&tracks(&queue)[..8]);
+
+        queue.set_shuffle(false, &mut rng);
+        assert_eq!(ids(&tracks(&queue)[..8]), played);
+        assert_eq!(queue.current, 7);
+
+        let upcoming = ids(&tracks(&queue)[8..]);
+        let mut sorted = upcoming.clone();
+        sorted.sort();
+        assert_eq!(upcoming, sorted);
+    }
+
+    #[test]
+    fn starting_while_shuffled_plays_the_chosen_track_first() {
+        let mut queue = PlayQueue::default();
+        let mut rng = WyRand::new_seed(0);
+
+        queue.set_shuffle(true, &mut rng);
+
+        assert_eq!(queue.start(test_tracks(10), Some(6), &mut rng).unwrap().id, test_track(6).id);
+        assert_eq!(queue.current, 0);
+    }
+
+    #[test]
+    fn insert_keeps_the_current_track() {
+        let mut queue = started_queue(3, Some(1), RepeatMode::None);
+
+        assert_eq!(queue.insert(0, test_track(10), false).id, test_track(10).id);
+        assert_eq!(queue.current().unwrap().id, test_track(1).id);
+
+        // Past the end appends.
+        queue.insert(100, test_track(11), false);
+        assert_eq!(tracks(&queue).last().unwrap().id, test_track(11).id);
+        assert_eq!(queue.current().unwrap().id, test_track(1).id);
+
+        queue.insert(1, test_track(12), true);
+        assert_eq!(queue.current().unwrap().id, test_track(12).id);
+
+        // Added tracks survive shuffling on and off.
+        let mut rng = WyRand::new_seed(0);
+        queue.set_shuffle(true, &mut rng);
+        queue.set_shuffle(false, &mut rng);
+        assert_eq!(queue.entries().len(), 6);
+    }
+
+    #[test]
+    fn entries_have_their_own_ids() {
+        let mut queue = PlayQueue::default();
+        let mut rng = WyRand::new_seed(0);
+
+        queue.start(vec![test_track(0), test_track(0)], None, &mut rng).unwrap();
+        queue.push(test_track(0));
+
+        let entry_ids: HashSet<u64> = queue.entries().iter().map(| entry | entry.id).collect();
+        assert_eq!(entry_ids.len(), 3);
+
+        // Starting a new queue doesn't reuse them either.
+        queue.start(vec![test_track(0)], None, &mut rng).unwrap();
+        assert!(!entry_ids.contains(&queue.entries()[0].id));
+    }
+
+    #[test]
+    fn insert_into_empty_queue() {
+        let mut queue = PlayQueue::default();
+
+        queue.insert(5, test_track(0), false);
+        assert_eq!(queue.current, 0);
+        assert_eq!(queue.current().unwrap().id, test_track(0).id);
+    }
+
+    #[test]
+    fn set_current_adds_missing_tracks() {
+        let mut queue = started_queue(3, Some(1), RepeatMode::None);
+
+        assert!(!queue.set_current(test_track(2)));
+        assert_eq!(queue.current, 2);
+
+        assert!(queue.set_current(test_track(10)));
+        assert_eq!(ids(&tracks(&queue)), ids(&[test_track(0), test_track(1), test_track(10), test_track(2)]));
+        assert_eq!(queue.current().unwrap().id, test_track(10).id);
+        assert_eq!(queue.peek_next().unwrap().id, test_track(2).id);
+
+        let mut queue = PlayQueue::default();
+        assert!(queue.set_current(test_track(0)));
+        assert_eq!(queue.current().unwrap().id, test_track(0).id);
+    }
+
+    #[test]
+    fn insert_next_and_push() {
+        let mut queue = PlayQueue::default();
+
+        queue.push(test_track(0));
+        queue.push(test_track(1));
+        queue.insert_next(test_track(2));
+
+        assert_eq!(ids(&tracks(&queue)), ids(&[test_track(0), test_track(2), test_track(1)]));
+        assert_eq!(queue.current().unwrap().id, test_track(0).id);
+
+        queue.go_to(2).unwrap();
+        queue.insert_next(test_track(3));
+        assert_eq!(tracks(&queue).last().unwrap().id, test_track(3).id);
+        assert_eq!(queue.peek_next().unwrap().id, test_track(3).id);
+    }
+
+    #[test]
+    fn played_next_tracks_stay_put_after_shuffling() {
+        let mut queue = started_queue(5, Some(1), RepeatMode::None);
+        let mut rng = WyRand::new_seed(0);
+
+        queue.insert_next(test_track(10));
+        let queued = ids(&tracks(&queue));
+
+        queue.set_shuffle(true, &mut rng);
+        queue.set_shuffle(false, &mut rng);
+        assert_eq!(ids(&tracks(&queue)), queued);
+        assert_eq!(queue.peek_next().unwrap().id, test_track(10).id);
+    }
+
+    #[test]
+    fn move_track_keeps_the_current_track() {
+        let mut queue = started_queue(4, Some(2), RepeatMode::None);
+
+        queue.move_track(0, 3).unwrap();
+        assert_eq!(ids(&tracks(&queue)), ids(&[test_track(1), test_track(2), test_track(3), test_track(0)]));
+        assert_eq!(queue.current().unwrap().id, test_track(2).id);
+
+        queue.move_track(3, 0).unwrap();
+        assert_eq!(ids(&tracks(&queue)), ids(&test_tracks(4)));
+        assert_eq!(queue.current().unwrap().id, test_track(2).id);
+
+        queue.move_track(2, 0).unwrap();
+        assert_eq!(queue.current, 0);
+        assert_eq!(queue.current().unwrap().id, test_track(2).id);
+
+        queue.move_track(3, 1).unwrap();
+        assert_eq!(queue.current().unwrap().id, test_track(2).id);
+
+        assert!(matches!(queue.move_track(0, 4), Err(WorkerError::BadQueueIndex)));
+        assert!(matches!(PlayQueue::default().move_track(0, 0), Err(WorkerError::EmptyQueue)));
+    }
+
+    #[test]
+    fn moved_tracks_stay_put_after_shuffling() {
+        let mut queue = started_queue(5, None, RepeatMode::None);
+        let mut rng = WyRand::new_seed(0);
+
+        queue.move_track(4, 1).unwrap();
+        let moved = ids(&tracks(&queue));
+
+        queue.set_shuffle(true, &mut rng);
+        queue.set_shuffle(false, &mut rng);
+        assert_eq!(ids(&tracks(&queue)), moved);
+    }
+
+    #[test]
+    fn clear_keeps_only_the_current_track() {
+        let mut queue = started_queue(4, Some(2), RepeatMode::Playlist);
+
+        queue.clear();
+        assert_eq!(ids(&tracks(&queue)), ids(&[test_track(2)]));
+        assert_eq!(queue.current().unwrap().id, test_track(2).id);
+
+        let mut queue = PlayQueue::default();
+        queue.clear();
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn remove_adjusts_the_current_track() {
+        let mut queue = started_queue(4, Some(2), RepeatMode::None);
+
+        assert!(!queue.remove(3).unwrap());
+        assert!(!queue.remove(0).unwrap());
+        assert_eq!(queue.current().unwrap().id, test_track(2).id);
+        assert!(matches!(queue.remove(2), Err(WorkerError::BadQueueIndex)));
+
+        // The last track was current, so we go back to the start.
+        assert!(queue.remove(1).unwrap());
+        assert_eq!(queue.current().unwrap().id, test_track(1).id);
+
+        assert!(queue.remove(0).unwrap());
+        assert!(queue.is_empty());
+        assert!(queue.current().is_none());
+    }
+}
@@ -0,0 +1,28 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::queue::PlayQueue;
+
+
+// What's needed to pick playback back up where the last session left it.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SavedSession {
+    pub queue: PlayQueue,
+    pub position_ms: u32
+}
+
+impl SavedSession {
+    pub fn load(path: &Path) -> Option<SavedSession> {
+        let data = std::fs::read_to_string(path).ok()?;
+        ron::from_str(&data).ok()
+    }
+
+    pub fn save(&self, path: &Path) {
+        if let Ok(data) = ron::to_string(self) {
+            if let Err(e) = std::fs::write(path, data) {
+                println!("Error saving playback session: {}", e);
+            }
+        }
+    }
+}
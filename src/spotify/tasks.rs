@@ -0,0 +1,227 @@
+use std::sync::Arc;
+use std::collections::HashMap;
+
+use nanorand::{Rng, WyRand};
+use tokio::sync::Mutex;
+
+use librespot::metadata::Playlist;
+use rspotify::model::{Id, TrackId, SearchResult, SearchType};
+
+use super::cache::{CacheHandler, CoverCache};
+use super::backend::{ApiBackend, PlaylistSummary};
+use super::{error, PlayableUri, PlayerStateUpdate, RepaintCallback, Result, StateTx, TrackInfo, WorkerResult, WorkerTask};
+
+
+// Everything tasks need from the worker, cheap to clone so they can run on their own.
+#[derive(Clone)]
+pub struct TaskContext {
+    pub api: Option<Arc<dyn ApiBackend>>,
+    pub api_cache_handler: Arc<Mutex<CacheHandler>>,
+    pub covers: CoverCache,
+
+    pub state_tx: StateTx,
+    pub repaint: RepaintCallback
+}
+
+impl TaskContext {
+    pub async fn run(self, task: WorkerTask) -> Result<Option<WorkerResult>> {
+        match task {
+            WorkerTask::Login(_) => unreachable!("logging in changes the worker's state, so it isn't run as a task"),
+            WorkerTask::GetRecentlyPlayed => unreachable!("the history belongs to the worker, so it answers right away"),
+
+            WorkerTask::GetUserPlaylists => {
+                self.fetch_user_playlists_task().await.map(| r | Some(WorkerResult::UserPlaylists(r)))
+            }
+            WorkerTask::GetFeaturedPlaylists => {
+                self.fetch_featured_playlists_task().await.map(| r | Some(WorkerResult::FeaturedPlaylists(r)))
+            }
+            WorkerTask::GetPlaylistTracksInfo(playlist) => {
+                self.fetch_playlist_tracks_info_task(playlist).await.map(| r | Some(WorkerResult::PlaylistTrackInfo(r)))
+            }
+            WorkerTask::GetRecommendationsForPlaylist(playlist) => {
+                self.get_recommendations_task(playlist).await.map(| r | Some(WorkerResult::PlaylistRecommendations(r)))
+            }
+            WorkerTask::Search(query, search_type) => {
+                self.search(query, search_type).await.map(| r | Some(WorkerResult::SearchResult(r)))
+            }
+            WorkerTask::AddTrackToPlaylist(track, playlist) => {
+                self.add_track_to_playlist_task(track, playlist).await.map(|_| None)
+            }
+            WorkerTask::RemoveTrackFromPlaylist(track, playlist) => {
+                self.remove_track_from_playlist_task(track, playlist).await.map(|_| None)
+            }
+        }
+    }
+
+    pub fn send_state(&self, state: PlayerStateUpdate) {
+        self.state_tx.send(state).unwrap();
+        (self.repaint)();
+    }
+
+    fn api(&self) -> Result<&dyn ApiBackend> {
+        Ok(self.api.as_deref().ok_or(error::WorkerError::NoAPIClient)?)
+    }
+
+    async fn fetch_user_playlists_task(&self) -> Result<Vec<(String, Playlist)>> {
+        let playlists = self.api()?.user_playlists().await?;
+        let playlists = self.process_playlist_info(playlists).await?;
+
+        let names = playlists.iter().map(| (id, p) | (id.clone(), p.name.clone())).collect();
+        self.send_state(PlayerStateUpdate::UserPlaylists(names));
+
+        Ok(playlists)
+    }
+
+    async fn fetch_featured_playlists_task(&self) -> Result<Vec<(String, Playlist)>> {
+        let featured = self.api()?.featured_playlists(5).await?;
+        self.process_playlist_info(featured).await
+    }
+
+    pub async fn fetch_playlist_tracks_info_task(&self, playlist: Playlist) -> Result<Vec<TrackInfo>> {
+        let track_ids = playlist.tracks.into_iter().map(|t| t.to_uri()).collect();
+        self.make_track_info_vec(track_ids).await
+    }
+
+    async fn search(&self, query: String, search_type: SearchType) -> Result<SearchResult> {
+        self.api()?.search(&query, search_type).await
+    }
+
+    async fn get_recommendations_task(&self, playlist: Playlist) -> Result<Vec<TrackInfo>> {
+        let mut playlist_tracks: Vec<String> = playlist.tracks.into_iter().map(| id | id.to_uri()).collect();
+
+        // The max amount of tracks for seeding you can use is 5, so shuffle them around
+        // and then grab the first 5 elements for our recommendation adventures.
+        WyRand::new().shuffle(&mut playlist_tracks);
+        playlist_tracks.truncate(5);
+
+        let tracks = self.api()?.recommendations(&playlist_tracks, 50).await?;
+        self.make_track_info_vec(tracks).await
+    }
+
+    async fn get_tracks_info(&self, tracks: &[String]) -> Result<Vec<TrackInfo>> {
+        if tracks.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let result = self.api()?.tracks(tracks).await?;
+
+        // Other tasks shouldn't have to wait on these, so they're downloaded before taking the lock.
+        for track in result.iter() {
+            self.covers.cache_cover_image(&track.album_id, &track.album_images).await;
+        }
+
+        if !result.is_empty() {
+            let mut cache = self.api_cache_handler.lock().await;
+
+            for track in result.iter() {
+                cache.cache_track_info(track.clone());
+            }
+
+            cache.save_cache().await;
+        }
+
+        Ok(result)
+    }
+
+    async fn add_track_to_playlist_task(&self, track: String, playlist: String) -> Result<()> {
+        self.api()?.add_track_to_playlist(&track, &playlist).await
+    }
+
+    async fn remove_track_from_playlist_task(&self, track: String, playlist: String) -> Result<()> {
+        self.api()?.remove_track_from_playlist(&track, &playlist).await
+    }
+
+    pub async fn fetch_playlist(&self, id: &str) -> Result<Playlist> {
+        self.api()?.playlist(id).await
+    }
+
+    pub async fn fetch_playlist_queue_task(&self, id: &str) -> Result<Vec<TrackInfo>> {
+        let playlist = self.fetch_playlist(id).await?;
+        let tracks = self.fetch_playlist_tracks_info_task(playlist).await?;
+
+        if tracks.is_empty() {
+            return Err(error::WorkerError::NoPlaylist.into());
+        }
+
+        Ok(tracks)
+    }
+
+    pub async fn fetch_uri_tracks_task(&self, uri: PlayableUri) -> Result<Vec<TrackInfo>> {
+        let tracks = match uri {
+            PlayableUri::Track(uri) => self.make_track_info_vec(vec![uri]).await?,
+            PlayableUri::Album(uri) => self.fetch_album_tracks_info(&uri).await?,
+            PlayableUri::Playlist(uri) => self.fetch_playlist_queue_task(&uri).await?
+        };
+
+        if tracks.is_empty() {
+            return Err(error::WorkerError::BadSpotifyId.into());
+        }
+
+        Ok(tracks)
+    }
+
+    pub async fn fetch_track_info_task(&self, uri: String) -> Result<TrackInfo> {
+        Ok(self.make_track_info_vec(vec![uri]).await?.pop().ok_or(error::WorkerError::BadSpotifyId)?)
+    }
+
+    pub async fn fetch_album_tracks_info(&self, id: &str) -> Result<Vec<TrackInfo>> {
+        let tracks = self.api()?.album_tracks(id).await?;
+        self.make_track_info_vec(tracks).await
+    }
+
+    async fn process_playlist_info(&self, playlists: Vec<PlaylistSummary>) -> Result<Vec<(String, Playlist)>> {
+        let api = self.api()?;
+        let mut result = Vec::new();
+
+        for playlist in playlists {
+            self.covers.cache_cover_image(&playlist.id, &playlist.images).await;
+
+            if let Ok(p) = api.playlist(&playlist.id).await {
+                result.push((playlist.id, p));
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Keeps the order the tracks were given in, whether they came from the cache or the API.
+    pub async fn make_track_info_vec(&self, tracks: Vec<String>) -> Result<Vec<TrackInfo>> {
+        let slots: Vec<(String, Option<TrackInfo>)> = {
+            let cache = self.api_cache_handler.lock().await;
+
+            tracks.into_iter()
+                // Only fetch tracks with a valid Spotify ID.
+                .filter(| uri | TrackId::from_uri(uri).is_ok())
+                .map(| uri | {
+                    let cached = cache.get_track_info(&uri);
+                    (uri, cached)
+                })
+                .collect()
+        };
+
+        // The covers should already be there, but making sure never killed anyone.
+        for track in slots.iter().filter_map(| (_, cached) | cached.as_ref()) {
+            self.covers.cache_cover_image(&track.album_id, &track.album_images).await;
+        }
+
+        // And only fetch the ones we don't already have cached.
+        let tracks_to_fetch: Vec<String> = slots.iter()
+            .filter(| (_, cached) | cached.is_none())
+            .map(| (uri, _) | uri.clone())
+            .collect()
+        ;
+
+        let fetched_tracks: HashMap<String, TrackInfo> = self.get_tracks_info(&tracks_to_fetch).await?
+            .into_iter()
+            .map(| track | (track.id.clone(), track))
+            .collect()
+        ;
+
+        let result = slots.into_iter()
+            .filter_map(| (uri, cached) | cached.or_else(|| fetched_tracks.get(&uri).cloned()))
+            .collect()
+        ;
+
+        Ok(result)
+    }
+}
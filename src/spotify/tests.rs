@@ -0,0 +1,573 @@
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+
+use super::*;
+use super::backend::ApiBackend;
+use super::fake::{FakeApi, FakePlayer, test_track, test_tracks};
+
+
+// A worker wired to the fakes, driven by calling its handlers directly.
+struct TestWorker {
+    worker: SpotifyWorker,
+    api: Arc<FakeApi>,
+    player: FakePlayer,
+    rng: WyRand,
+
+    result_rx: TaskResultRx,
+    // Sending state fails without receivers, so keep one around.
+    state_rx: StateRx,
+
+    cache_dir: PathBuf
+}
+
+impl TestWorker {
+    fn new(api: FakeApi) -> TestWorker {
+        static NEXT_CACHE_DIR: AtomicUsize = AtomicUsize::new(0);
+
+        let cache_dir = std::env::temp_dir().join(format!(
+            "espot-rs-test-{}-{}",
+            std::process::id(),
+            NEXT_CACHE_DIR.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        TestWorker::in_dir(api, cache_dir)
+    }
+
+    // Shares the cache dir with other workers, like a restarted app would.
+    fn in_dir(api: FakeApi, cache_dir: PathBuf) -> TestWorker {
+        let (mut worker, _, result_rx, state_rx, _) = SpotifyWorker::new(cache_dir.clone(), Arc::new(|| {}));
+
+        let api = Arc::new(api);
+        let player = FakePlayer::default();
+
+        worker.context.api = Some(api.clone() as Arc<dyn ApiBackend>);
+        worker.spotify_player = Some(Box::new(player.clone()));
+
+        TestWorker {
+            worker,
+            api,
+            player,
+            rng: WyRand::new_seed(0),
+
+            result_rx,
+            state_rx,
+
+            cache_dir
+        }
+    }
+
+    // Handles a control and whatever it started, like the worker loop would.
+    async fn control(&mut self, control: PlayerControl) {
+        self.worker.handle_control(control, &mut self.rng);
+        self.finish_tasks().await;
+    }
+
+    async fn finish_tasks(&mut self) {
+        while !self.worker.running_tasks.is_empty() {
+            let done = self.worker.task_done_rx.recv().await.unwrap();
+            self.worker.handle_task_done(done, &mut self.rng);
+        }
+    }
+
+    fn queue_ids(&self) -> Vec<String> {
+        self.worker.player_queue.entries().iter().map(| entry | entry.track.id.clone()).collect()
+    }
+
+    fn current_track_id(&self) -> String {
+        self.worker.player_queue.current().unwrap().id.clone()
+    }
+
+    fn last_loaded(&self) -> Option<SpotifyId> {
+        self.player.state.lock().unwrap().loaded.last().copied()
+    }
+
+    fn is_playing(&self) -> bool {
+        self.player.state.lock().unwrap().playing
+    }
+
+    // The most recent queue update sent since the last call.
+    fn last_queue_update(&mut self) -> Option<(Vec<String>, usize)> {
+        let mut last = None;
+
+        loop {
+            match self.state_rx.try_recv() {
+                Ok(PlayerStateUpdate::Queue(entries, current)) => {
+                    last = Some((entries.into_iter().map(| entry | entry.track.id).collect(), current));
+                }
+                // Only the oldest updates are dropped, the last one is still there.
+                Ok(_) | Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+                Err(_) => break
+            }
+        }
+
+        last
+    }
+}
+
+impl Drop for TestWorker {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.cache_dir);
+    }
+}
+
+fn spotify_id(track: &TrackInfo) -> SpotifyId {
+    SpotifyId::from_uri(&track.id).unwrap()
+}
+
+#[tokio::test]
+async fn starting_playlist_by_id_loads_first_track() {
+    let tracks = test_tracks(3);
+    let mut api = FakeApi::default();
+    api.add_playlist("spotify:playlist:test", &tracks);
+
+    let mut test = TestWorker::new(api);
+    test.control(PlayerControl::StartPlaylistById(String::from("spotify:playlist:test"))).await;
+
+    assert_eq!(test.queue_ids(), tracks.iter().map(| t | t.id.clone()).collect::<Vec<String>>());
+    assert_eq!(test.current_track_id(), tracks[0].id);
+    assert_eq!(test.last_loaded(), Some(spotify_id(&tracks[0])));
+    assert!(test.is_playing());
+}
+
+#[tokio::test]
+async fn only_the_last_started_playlist_plays() {
+    let tracks = test_tracks(4);
+    let mut api = FakeApi::default();
+    api.add_playlist("spotify:playlist:first", &tracks[..2]);
+    api.add_playlist("spotify:playlist:second", &tracks[2..]);
+
+    let mut test = TestWorker::new(api);
+    test.worker.handle_control(PlayerControl::StartPlaylistById(String::from("spotify:playlist:first")), &mut test.rng);
+    test.worker.handle_control(PlayerControl::StartPlaylistById(String::from("spotify:playlist:second")), &mut test.rng);
+    test.finish_tasks().await;
+
+    assert_eq!(test.queue_ids().len(), 2);
+    assert_eq!(test.current_track_id(), tracks[2].id);
+}
+
+#[tokio::test]
+async fn playing_an_album_uri_starts_its_tracks() {
+    let tracks = test_tracks(3);
+    let mut api = FakeApi::default();
+
+    for track in &tracks {
+        api.add_track(track.clone());
+    }
+
+    api.albums.insert(String::from("spotify:album:test"), tracks.iter().map(| t | t.id.clone()).collect());
+
+    let mut test = TestWorker::new(api);
+    test.control(PlayerControl::SetShuffle(false)).await;
+    test.control(PlayerControl::PlayUri(PlayableUri::Album(String::from("spotify:album:test")))).await;
+
+    assert_eq!(test.queue_ids(), tracks.iter().map(| t | t.id.clone()).collect::<Vec<String>>());
+    assert_eq!(test.last_loaded(), Some(spotify_id(&tracks[0])));
+}
+
+#[tokio::test]
+async fn next_and_previous_follow_repeat_mode() {
+    let tracks = test_tracks(3);
+    let mut test = TestWorker::new(FakeApi::default());
+
+    test.control(PlayerControl::SetRepeat(RepeatMode::None)).await;
+    test.control(PlayerControl::StartPlaylist(tracks.clone())).await;
+    test.control(PlayerControl::NextTrack).await;
+    test.control(PlayerControl::NextTrack).await;
+    assert_eq!(test.last_loaded(), Some(spotify_id(&tracks[2])));
+
+    // Without repeat, going past the last track stops playback.
+    test.control(PlayerControl::NextTrack).await;
+    assert_eq!(test.current_track_id(), tracks[2].id);
+    assert!(!test.is_playing());
+
+    test.control(PlayerControl::SetRepeat(RepeatMode::Playlist)).await;
+    test.control(PlayerControl::NextTrack).await;
+    assert_eq!(test.last_loaded(), Some(spotify_id(&tracks[0])));
+
+    test.control(PlayerControl::PreviousTrack).await;
+    assert_eq!(test.last_loaded(), Some(spotify_id(&tracks[2])));
+
+    // Repeating a track only applies when it ends, skipping still moves on.
+    test.control(PlayerControl::SetRepeat(RepeatMode::Track)).await;
+    test.worker.handle_player_event(PlayerEvent::EndOfTrack { play_request_id: 0, track_id: spotify_id(&tracks[2]) });
+    assert_eq!(test.current_track_id(), tracks[2].id);
+
+    test.control(PlayerControl::NextTrack).await;
+    assert_eq!(test.current_track_id(), tracks[0].id);
+}
+
+#[tokio::test]
+async fn shuffle_keeps_played_tracks_and_unshuffle_restores_order() {
+    let tracks = test_tracks(10);
+    let original: Vec<String> = tracks.iter().map(| t | t.id.clone()).collect();
+    let mut test = TestWorker::new(FakeApi::default());
+
+    test.control(PlayerControl::StartPlaylist(tracks.clone())).await;
+    test.control(PlayerControl::GoToTrack(3)).await;
+    let loads = test.player.state.lock().unwrap().loaded.len();
+
+    test.control(PlayerControl::SetShuffle(true)).await;
+    assert_eq!(test.queue_ids()[..4], original[..4]);
+    assert_eq!(test.current_track_id(), tracks[3].id);
+
+    let mut shuffled = test.queue_ids();
+    shuffled.sort();
+    assert_eq!(shuffled, original);
+
+    test.control(PlayerControl::SetShuffle(false)).await;
+    assert_eq!(test.queue_ids(), original);
+    assert_eq!(test.current_track_id(), tracks[3].id);
+
+    // Reordering the queue shouldn't interrupt what's playing.
+    assert_eq!(test.player.state.lock().unwrap().loaded.len(), loads);
+}
+
+#[tokio::test]
+async fn queue_edits_keep_current_track() {
+    let tracks = test_tracks(3);
+    let extra = test_track(100);
+
+    let mut api = FakeApi::default();
+    api.add_track(extra.clone());
+
+    let mut test = TestWorker::new(api);
+    test.control(PlayerControl::StartPlaylist(tracks.clone())).await;
+    test.control(PlayerControl::GoToTrack(1)).await;
+
+    test.control(PlayerControl::AddTrack(extra.id.clone(), 0, false)).await;
+    assert_eq!(test.queue_ids()[0], extra.id);
+    assert_eq!(test.current_track_id(), tracks[1].id);
+
+    test.control(PlayerControl::RemoveTrack(0)).await;
+    assert_eq!(test.current_track_id(), tracks[1].id);
+
+    // Removing the current track plays whatever came after it.
+    test.control(PlayerControl::RemoveTrack(1)).await;
+    assert_eq!(test.current_track_id(), tracks[2].id);
+    assert_eq!(test.last_loaded(), Some(spotify_id(&tracks[2])));
+
+    test.control(PlayerControl::AddTrack(extra.id.clone(), 0, true)).await;
+    assert_eq!(test.current_track_id(), extra.id);
+    assert_eq!(test.last_loaded(), Some(spotify_id(&extra)));
+}
+
+#[tokio::test]
+async fn cached_tracks_are_not_fetched_again() {
+    let tracks = test_tracks(3);
+    let ids: Vec<String> = tracks.iter().map(| t | t.id.clone()).collect();
+
+    let mut api = FakeApi::default();
+    for track in tracks {
+        api.add_track(track);
+    }
+
+    let test = TestWorker::new(api);
+
+    let first = test.worker.context.make_track_info_vec(ids.clone()).await.unwrap();
+    let second = test.worker.context.make_track_info_vec(ids).await.unwrap();
+
+    assert_eq!(first.len(), 3);
+    assert_eq!(second.len(), 3);
+    assert_eq!(test.api.tracks_requested.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn cached_and_fetched_tracks_keep_their_order() {
+    let tracks = test_tracks(5);
+    let ids: Vec<String> = tracks.iter().map(| t | t.id.clone()).collect();
+
+    let mut api = FakeApi::default();
+    api.add_playlist("spotify:playlist:test", &tracks);
+
+    let mut test = TestWorker::new(api);
+
+    // Only some of the tracks are cached, the rest have to be fetched.
+    test.worker.context.make_track_info_vec(vec![ids[1].clone(), ids[3].clone()]).await.unwrap();
+
+    test.control(PlayerControl::SetShuffle(false)).await;
+    test.control(PlayerControl::StartPlaylistById(String::from("spotify:playlist:test"))).await;
+
+    assert_eq!(test.queue_ids(), ids);
+    assert_eq!(test.current_track_id(), tracks[0].id);
+    assert_eq!(test.api.tracks_requested.load(Ordering::SeqCst), 5);
+}
+
+#[tokio::test]
+async fn api_failures_are_reported() {
+    let mut api = FakeApi::default();
+    api.add_playlist("spotify:playlist:test", &test_tracks(3));
+    api.failing.store(true, Ordering::SeqCst);
+
+    let mut test = TestWorker::new(api);
+    test.control(PlayerControl::StartPlaylistById(String::from("spotify:playlist:test"))).await;
+
+    match test.result_rx.try_recv() {
+        Ok((None, WorkerResult::Error(TaskKind::StartPlaylist, TaskError::Io(_)))) => {}
+        result => panic!("unexpected result: {:?}", result)
+    }
+
+    assert!(test.worker.player_queue.is_empty());
+    assert_eq!(test.last_loaded(), None);
+}
+
+#[tokio::test]
+async fn task_results_are_tagged_with_their_request() {
+    let mut api = FakeApi::default();
+    api.add_playlist("spotify:playlist:test", &test_tracks(3));
+
+    let mut test = TestWorker::new(api);
+    let id = RequestId::next();
+
+    test.worker.handle_task(id, WorkerTask::GetUserPlaylists);
+
+    match test.worker.task_done_rx.recv().await {
+        Some(TaskDone::Finished(done_id, TaskKind::GetUserPlaylists, Ok(Some(WorkerResult::UserPlaylists(playlists))))) => {
+            assert_eq!(done_id, id);
+            assert_eq!(playlists.len(), 1);
+        }
+        _ => panic!("unexpected task result")
+    }
+}
+
+#[tokio::test]
+async fn cancelled_tasks_send_nothing() {
+    let mut test = TestWorker::new(FakeApi::default());
+    let id = RequestId::next();
+
+    // Tests run on a single thread, so the task can't start before it's cancelled.
+    test.worker.handle_task(id, WorkerTask::GetUserPlaylists);
+    test.worker.cancel_task(id);
+    tokio::task::yield_now().await;
+
+    assert!(test.worker.running_tasks.is_empty());
+    assert!(test.worker.task_done_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn empty_queue_controls_dont_panic() {
+    let mut test = TestWorker::new(FakeApi::default());
+
+    test.control(PlayerControl::StartPlaylist(Vec::new())).await;
+
+    match test.result_rx.try_recv() {
+        Ok((None, WorkerResult::Error(TaskKind::StartPlaylist, TaskError::Worker(error::WorkerError::EmptyQueue)))) => {}
+        result => panic!("unexpected result: {:?}", result)
+    }
+
+    test.control(PlayerControl::NextTrack).await;
+    test.control(PlayerControl::PreviousTrack).await;
+    test.control(PlayerControl::GoToTrack(0)).await;
+    test.control(PlayerControl::RemoveTrack(0)).await;
+    test.control(PlayerControl::SetShuffle(true)).await;
+    test.control(PlayerControl::SeekRelative(1000)).await;
+    test.worker.handle_player_event(PlayerEvent::TimeToPreloadNextTrack { play_request_id: 0, track_id: spotify_id(&test_track(0)) });
+
+    assert!(test.worker.player_queue.is_empty());
+    assert_eq!(test.last_loaded(), None);
+}
+
+#[tokio::test]
+async fn queueing_tracks_from_the_ui() {
+    let tracks = test_tracks(3);
+    let mut test = TestWorker::new(FakeApi::default());
+
+    // Nothing was playing, so the first track added starts right away.
+    test.control(PlayerControl::AddToQueue(tracks[0].clone())).await;
+    assert_eq!(test.last_loaded(), Some(spotify_id(&tracks[0])));
+
+    test.control(PlayerControl::AddToQueue(tracks[1].clone())).await;
+    test.control(PlayerControl::PlayNext(tracks[2].clone())).await;
+    assert_eq!(test.queue_ids(), vec![tracks[0].id.clone(), tracks[2].id.clone(), tracks[1].id.clone()]);
+    assert_eq!(test.last_loaded(), Some(spotify_id(&tracks[0])));
+
+    test.control(PlayerControl::MoveTrack(2, 1)).await;
+    assert_eq!(test.queue_ids(), vec![tracks[0].id.clone(), tracks[1].id.clone(), tracks[2].id.clone()]);
+
+    test.control(PlayerControl::ClearQueue).await;
+    assert_eq!(test.queue_ids(), vec![tracks[0].id.clone()]);
+    assert_eq!(test.current_track_id(), tracks[0].id);
+}
+
+#[tokio::test]
+async fn queue_updates_follow_the_current_track() {
+    let tracks = vec![test_track(0), test_track(1), test_track(0)];
+    let ids: Vec<String> = tracks.iter().map(| t | t.id.clone()).collect();
+    let mut test = TestWorker::new(FakeApi::default());
+
+    test.control(PlayerControl::StartPlaylist(tracks)).await;
+    assert_eq!(test.last_queue_update(), Some((ids.clone(), 0)));
+
+    // The same track twice, the index tells which one is playing.
+    test.control(PlayerControl::GoToTrack(2)).await;
+    assert_eq!(test.last_queue_update(), Some((ids.clone(), 2)));
+
+    test.control(PlayerControl::SetRepeat(RepeatMode::Playlist)).await;
+    test.control(PlayerControl::NextTrack).await;
+    assert_eq!(test.last_queue_update(), Some((ids, 0)));
+}
+
+#[tokio::test]
+async fn previous_walks_the_history() {
+    let tracks = test_tracks(3);
+    let other = test_track(100);
+    let mut test = TestWorker::new(FakeApi::default());
+
+    // Something played before the current queue was started.
+    test.control(PlayerControl::StartPlaylist(vec![other.clone()])).await;
+    test.control(PlayerControl::StartPlaylist(tracks.clone())).await;
+    test.control(PlayerControl::GoToTrack(2)).await;
+
+    // Track 1 was skipped, so going back lands on track 0.
+    test.control(PlayerControl::PreviousTrack).await;
+    assert_eq!(test.current_track_id(), tracks[0].id);
+
+    // Going back past the queue brings that track back into it, right before the current one.
+    test.control(PlayerControl::PreviousTrack).await;
+    assert_eq!(test.current_track_id(), other.id);
+    assert_eq!(test.queue_ids()[0], other.id);
+    assert_eq!(test.last_loaded(), Some(spotify_id(&other)));
+
+    // Moving forward again retraces the history instead of following the queue.
+    test.control(PlayerControl::NextTrack).await;
+    assert_eq!(test.current_track_id(), tracks[0].id);
+    test.control(PlayerControl::NextTrack).await;
+    assert_eq!(test.current_track_id(), tracks[2].id);
+
+    let recent: Vec<String> = test.worker.player_history.recent(10).into_iter().map(| t | t.id).collect();
+    assert_eq!(recent, vec![tracks[2].id.clone(), tracks[0].id.clone(), other.id.clone()]);
+}
+
+#[tokio::test]
+async fn preloading_follows_the_history() {
+    let tracks = test_tracks(3);
+    let mut test = TestWorker::new(FakeApi::default());
+
+    test.control(PlayerControl::StartPlaylist(tracks.clone())).await;
+    test.control(PlayerControl::GoToTrack(2)).await;
+    test.control(PlayerControl::PreviousTrack).await;
+
+    // The queue would go on to track 1, but moving forward retraces the history.
+    test.worker.handle_player_event(PlayerEvent::TimeToPreloadNextTrack { play_request_id: 0, track_id: spotify_id(&tracks[0]) });
+    assert_eq!(test.player.state.lock().unwrap().preloaded.last(), Some(&spotify_id(&tracks[2])));
+
+    test.worker.handle_player_event(PlayerEvent::EndOfTrack { play_request_id: 0, track_id: spotify_id(&tracks[0]) });
+    assert_eq!(test.last_loaded(), Some(spotify_id(&tracks[2])));
+}
+
+#[tokio::test]
+async fn recently_played_is_answered_right_away() {
+    let tracks = test_tracks(2);
+    let mut test = TestWorker::new(FakeApi::default());
+
+    test.control(PlayerControl::StartPlaylist(tracks.clone())).await;
+    test.control(PlayerControl::NextTrack).await;
+
+    let id = RequestId::next();
+    test.worker.handle_task(id, WorkerTask::GetRecentlyPlayed);
+
+    match test.result_rx.try_recv() {
+        Ok((Some(result_id), WorkerResult::RecentlyPlayed(recent))) => {
+            assert_eq!(result_id, id);
+            assert_eq!(recent.into_iter().map(| t | t.id).collect::<Vec<String>>(), vec![tracks[1].id.clone(), tracks[0].id.clone()]);
+        }
+        result => panic!("unexpected result: {:?}", result)
+    }
+}
+
+#[tokio::test]
+async fn sessions_are_restored_paused() {
+    let tracks = test_tracks(3);
+    let mut test = TestWorker::new(FakeApi::default());
+
+    test.control(PlayerControl::SetRepeat(RepeatMode::Track)).await;
+    test.control(PlayerControl::StartPlaylist(tracks.clone())).await;
+    test.control(PlayerControl::GoToTrack(1)).await;
+    test.control(PlayerControl::Seek(42_000)).await;
+    test.worker.player_paused = true;
+    test.worker.save_session();
+
+    let mut restored = TestWorker::in_dir(FakeApi::default(), test.cache_dir.clone());
+    restored.worker.restore_session();
+
+    assert_eq!(restored.queue_ids(), test.queue_ids());
+    assert_eq!(restored.current_track_id(), tracks[1].id);
+    assert_eq!(restored.worker.player_queue.repeat(), RepeatMode::Track);
+    assert!(restored.worker.player_paused);
+
+    let state = restored.player.state.lock().unwrap();
+    assert_eq!(state.loaded, vec![spotify_id(&tracks[1])]);
+    assert_eq!(state.position_ms, 42_000);
+    assert!(!state.playing);
+}
+
+#[tokio::test]
+async fn positions_are_sent_apart_from_other_updates() {
+    let mut test = TestWorker::new(FakeApi::default());
+    let position_rx = test.worker.position_tx.subscribe();
+
+    test.control(PlayerControl::StartPlaylist(test_tracks(3))).await;
+    test.worker.update_position();
+
+    let track_id = position_rx.borrow().as_ref().map(| position | position.track_id.clone());
+    assert_eq!(track_id, Some(test.current_track_id()));
+
+    test.control(PlayerControl::Stop).await;
+    assert!(position_rx.borrow().is_none());
+}
+
+#[tokio::test]
+async fn sessions_are_saved_while_playing() {
+    let tracks = test_tracks(3);
+    let mut test = TestWorker::new(FakeApi::default());
+
+    test.control(PlayerControl::StartPlaylist(tracks.clone())).await;
+    test.control(PlayerControl::GoToTrack(2)).await;
+    test.control(PlayerControl::Seek(42_000)).await;
+
+    // Nothing's saved mid-track until it's been a while since the last save.
+    test.worker.update_position();
+    assert_eq!(SavedSession::load(&test.worker.session_path).unwrap().position_ms, 0);
+
+    test.worker.session_last_save = Instant::now().checked_sub(SESSION_SAVE_INTERVAL).unwrap();
+    test.worker.update_position();
+
+    let mut restored = TestWorker::in_dir(FakeApi::default(), test.cache_dir.clone());
+    restored.worker.restore_session();
+
+    assert_eq!(restored.current_track_id(), tracks[2].id);
+
+    let state = restored.player.state.lock().unwrap();
+    assert_eq!(state.loaded, vec![spotify_id(&tracks[2])]);
+    assert!((42_000..43_000).contains(&state.position_ms));
+}
+
+#[tokio::test]
+async fn sessions_are_saved_on_shutdown() {
+    let tracks = test_tracks(3);
+    let mut test = TestWorker::new(FakeApi::default());
+
+    test.control(PlayerControl::StartPlaylist(tracks.clone())).await;
+    test.control(PlayerControl::Seek(42_000)).await;
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    shutdown_tx.send(()).unwrap();
+    test.worker.process_events(shutdown_rx).await;
+    assert!(!test.is_playing());
+
+    let mut restored = TestWorker::in_dir(FakeApi::default(), test.cache_dir.clone());
+    restored.worker.restore_session();
+
+    let state = restored.player.state.lock().unwrap();
+    assert_eq!(state.loaded, vec![spotify_id(&tracks[0])]);
+    assert!((42_000..43_000).contains(&state.position_ms));
+}
+
+#[tokio::test]
+async fn nothing_is_restored_without_a_session() {
+    let mut test = TestWorker::new(FakeApi::default());
+    test.worker.restore_session();
+
+    assert!(test.worker.player_queue.is_empty());
+    assert_eq!(test.last_loaded(), None);
+}
@@ -1,21 +1,43 @@
 mod utils;
 
+use std::sync::Arc;
 use std::path::PathBuf;
 
 use eframe::egui;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, watch};
 
 use librespot::metadata::Playlist;
 use rspotify::model::{SearchResult, SearchType};
 
 use crate::spotify::*;
+use crate::config::{Config, Theme};
 
+#[derive(Default)]
 enum CurrentPanel {
+    #[default]
     Home,
-    Search { query: String, search_type: SearchType, result: Option<SearchResult>, tracks_info: Vec<TrackInfo>, waiting_for_info: bool },
-    Playlist { id: String, data: Playlist, tracks_info: Vec<TrackInfo>, waiting_for_info: bool },
-    Recommendations { tracks_info: Vec<TrackInfo>, waiting_for_info: bool }
+    Search { query: String, search_type: SearchType, result: Option<SearchResult>, tracks_info: Vec<TrackInfo>, waiting_for_info: bool, request: Option<RequestId> },
+    Playlist { id: String, data: Playlist, tracks_info: Vec<TrackInfo>, waiting_for_info: bool, request: RequestId },
+    Recommendations { tracks_info: Vec<TrackInfo>, waiting_for_info: bool, request: RequestId },
+    RecentlyPlayed { tracks_info: Vec<TrackInfo>, waiting_for_info: bool, request: RequestId },
+    // Index of the track being dragged to a new position, if any.
+    Queue { dragged: Option<usize> },
+    // Settings being edited, only applied once the user's done with them.
+    Settings { config: Config }
+}
+
+impl CurrentPanel {
+    // The worker request the panel's contents are coming from.
+    fn request(&self) -> Option<RequestId> {
+        match self {
+            CurrentPanel::Home | CurrentPanel::Queue { .. } | CurrentPanel::Settings { .. } => None,
+            CurrentPanel::Search { request, .. } => *request,
+            CurrentPanel::Playlist { request, .. } => Some(*request),
+            CurrentPanel::Recommendations { request, .. } => Some(*request),
+            CurrentPanel::RecentlyPlayed { request, .. } => Some(*request)
+        }
+    }
 }
 
 // PartialEq on CurrentPanel is only used to determine which panel is selected,
@@ -26,14 +48,25 @@ impl PartialEq for CurrentPanel {
             (CurrentPanel::Search { .. }, CurrentPanel::Search { .. }) => true,
             (CurrentPanel::Playlist { .. }, CurrentPanel::Playlist { .. }) => true,
             (CurrentPanel::Recommendations { .. }, CurrentPanel::Recommendations { .. }) => true,
+            (CurrentPanel::Queue { .. }, CurrentPanel::Queue { .. }) => true,
+            (CurrentPanel::RecentlyPlayed { .. }, CurrentPanel::RecentlyPlayed { .. }) => true,
+            (CurrentPanel::Settings { .. }, CurrentPanel::Settings { .. }) => true,
             _ => core::mem::discriminant(self) == core::mem::discriminant(other),
         }
     }
 }
 
-impl Default for CurrentPanel {
-    fn default() -> CurrentPanel {
-        CurrentPanel::Home
+fn is_panel_result(result: &WorkerResult) -> bool {
+    match result {
+        WorkerResult::SearchResult(_) | WorkerResult::PlaylistTrackInfo(_) | WorkerResult::PlaylistRecommendations(_) => true,
+        WorkerResult::RecentlyPlayed(_) => true,
+        WorkerResult::Error(kind, _) => {
+            matches!(
+                kind,
+                TaskKind::Search | TaskKind::GetPlaylistTracksInfo | TaskKind::GetRecommendationsForPlaylist | TaskKind::GetRecentlyPlayed
+            )
+        }
+        _ => false
     }
 }
 
@@ -41,21 +74,63 @@ impl Default for CurrentPanel {
 struct PlaybackStatus {
     paused: bool,
     started: bool,
+    shuffle: bool,
+    repeat: RepeatMode,
+    // Only known once the worker has a player.
+    bitrate: Option<Bitrate>,
+
+    current_track: Option<TrackInfo>,
+    queue: Vec<QueueEntry>,
+    // Index of the current track in the queue.
+    queue_current: usize,
+
+    position: Option<PlaybackPosition>,
+    // Position the user is dragging the progress bar to, if any.
+    seek_preview_ms: Option<u32>
+}
+
+impl PlaybackStatus {
+    fn position_ms(&self) -> u32 {
+        match (self.current_track.as_ref(), self.position.as_ref()) {
+            (Some(track), Some(position)) if track.id == position.track_id => {
+                position.current_position_ms().min(track.duration_ms as u32)
+            }
+            _ => 0
+        }
+    }
+}
 
-    current_track: Option<TrackInfo>
+// Requests for the app itself, rather than the player, coming from outside of the UI.
+#[cfg_attr(not(all(target_os = "linux", feature = "mpris")), allow(dead_code))]
+#[derive(Debug)]
+pub enum AppCommand {
+    Quit
 }
 
 #[derive(Deserialize, Serialize)]
 struct PersistentData {
-    cache_path: PathBuf,
-
     login_username: String,
-    login_remember: bool
+    login_remember: bool,
+
+    #[serde(default = "default_volume")]
+    volume: u16
+}
+
+fn default_volume() -> u16 {
+    u16::MAX
 }
 
 #[derive(Default)]
 struct VolatileData {
+    // The one the worker was started with, changing it needs a restart.
+    cache_path: PathBuf,
+    // What eframe found for the system, before the user's settings are applied.
+    system_dark_mode: Option<bool>,
+    native_pixels_per_point: f32,
+
     logged_in: bool,
+    // Only lasts until the app is closed, unlike the bitrate in the settings.
+    data_saver: bool,
     login_password: String,
     keyring_login_attempted: bool,
     waiting_for_login_result: bool,
@@ -70,11 +145,17 @@ struct VolatileData {
 
     playback_status: PlaybackStatus,
 
+    // Failed tasks reported by the worker, shown until dismissed.
+    task_errors: Vec<(TaskKind, String)>,
+
     state_rx: Option<broadcast::Receiver<PlayerStateUpdate>>,
+    position_rx: Option<watch::Receiver<Option<PlaybackPosition>>>,
     control_tx: Option<mpsc::UnboundedSender<PlayerControl>>,
+    app_command_rx: Option<mpsc::UnboundedReceiver<AppCommand>>,
 
-    worker_task_tx: Option<mpsc::UnboundedSender<WorkerTask>>,
-    worker_result_rx: Option<mpsc::UnboundedReceiver<WorkerResult>>,
+    worker_task_tx: Option<mpsc::UnboundedSender<WorkerRequest>>,
+    worker_result_rx: Option<mpsc::UnboundedReceiver<(Option<RequestId>, WorkerResult)>>,
+    worker_shutdown: Option<WorkerShutdown>,
 
     texture_no_cover: Option<egui::TextureHandle>,
     texture_album_cover: Option<egui::TextureHandle>,
@@ -87,23 +168,27 @@ struct VolatileData {
 pub struct EspotApp {
     p: PersistentData,
     #[serde(skip)]
-    v: VolatileData
+    v: VolatileData,
+    // Kept in its own file, rather than in eframe's storage.
+    #[serde(skip)]
+    config: Config
 }
 
 impl Default for EspotApp {
     fn default() -> EspotApp {
         let p = PersistentData {
-            cache_path: dirs::cache_dir().unwrap().join("espot-rs"),
-
             login_username: String::new(),
-            login_remember: false
+            login_remember: false,
+
+            volume: default_volume()
         };
 
         let v = VolatileData::default();
 
         EspotApp {
             p,
-            v
+            v,
+            config: Config::default()
         }
     }
 }
@@ -113,7 +198,14 @@ impl eframe::App for EspotApp {
         eframe::set_value(storage, eframe::APP_KEY, self);
     }
 
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+    fn on_exit(&mut self, _gl: &eframe::glow::Context) {
+        // The process exits as soon as this returns, so wait for the worker to save the session.
+        if let Some(shutdown) = self.v.worker_shutdown.take() {
+            shutdown.shutdown();
+        }
+    }
+
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         if self.v.texture_no_cover.is_none() {
             let buffer = include_bytes!("../../resources/no_cover.png");
             self.v.texture_no_cover = utils::create_texture_from_bytes(ctx, buffer);
@@ -121,7 +213,7 @@ impl eframe::App for EspotApp {
 
         if let Some(track) = self.v.playback_status.current_track.as_ref() {
             if self.v.texture_album_cover.is_none() {
-                let path = self.p.cache_path.join(format!("cover-{}", &track.album_id));
+                let path = self.v.cache_path.join(format!("cover-{}", &track.album_id));
                 self.v.texture_album_cover = utils::create_texture_from_file(ctx, path);
             }
         }
@@ -129,7 +221,7 @@ impl eframe::App for EspotApp {
         for (i, target) in self.v.textures_user_playlists_covers.iter_mut().enumerate() {
             if target.is_none() {
                 let (playlist_id, _) = &self.v.user_playlists[i];
-                let path = self.p.cache_path.join(format!("cover-{}", playlist_id));
+                let path = self.v.cache_path.join(format!("cover-{}", playlist_id));
             
                 *target = utils::create_texture_from_file(ctx, path);
             }
@@ -138,21 +230,24 @@ impl eframe::App for EspotApp {
         for (i, target) in self.v.textures_featured_playlists_covers.iter_mut().enumerate() {
             if target.is_none() {
                 let (playlist_id, _) = &self.v.featured_playlists[i];
-                let path = self.p.cache_path.join(format!("cover-{}", playlist_id));
+                let path = self.v.cache_path.join(format!("cover-{}", playlist_id));
             
                 *target = utils::create_texture_from_file(ctx, path);
             }
         }
 
+        self.draw_task_errors(ctx);
+
         if self.v.logged_in {
             self.draw_main_screen(ctx);
 
-            if self.v.user_playlists.is_empty() && !self.v.fetching_user_playlists {
+            // Don't keep retrying while the previous failure is still on screen.
+            if self.v.user_playlists.is_empty() && !self.v.fetching_user_playlists && !self.has_task_error(TaskKind::GetUserPlaylists) {
                 self.v.fetching_user_playlists = true;
                 self.send_worker_msg(WorkerTask::GetUserPlaylists);
             }
 
-            if self.v.featured_playlists.is_empty() && !self.v.fetching_featured_playlists {
+            if self.v.featured_playlists.is_empty() && !self.v.fetching_featured_playlists && !self.has_task_error(TaskKind::GetFeaturedPlaylists) {
                 self.v.fetching_featured_playlists = true;
                 self.send_worker_msg(WorkerTask::GetFeaturedPlaylists);
             }
@@ -162,43 +257,65 @@ impl eframe::App for EspotApp {
         }
 
         self.handle_messages();
-
-        // TODO: Workaround for not being able to figure out how to request a repaint
-        //       from the worker thread. Burns more resources than needed.
-        ctx.request_repaint();
+        self.handle_app_commands(frame);
     }
 }
 
 impl EspotApp {
     pub fn new(cc: &eframe::CreationContext<'_>) -> EspotApp {
         let mut app = EspotApp::default();
+        let mut app_storage = None;
 
         if let Some(storage) = cc.storage {
-            app = eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default()
+            app = eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+            app_storage = storage.get_string(eframe::APP_KEY);
         }
 
+        app.config = Config::load(app_storage);
+        app.v.cache_path = app.config.cache_path.clone();
+        app.v.system_dark_mode = cc.integration_info.prefer_dark_mode;
+        app.v.native_pixels_per_point = cc.integration_info.native_pixels_per_point.unwrap_or(1.0);
+
         if app.v.worker_task_tx.is_none() {
+            // Lets the worker and the dbus server wake the UI up when they have something for it.
+            let ctx = cc.egui_ctx.clone();
+            let repaint: RepaintCallback = Arc::new(move || ctx.request_repaint());
+
+            // The dbus server is optional, which can leave its receiver unused.
+            #[cfg_attr(not(all(target_os = "linux", feature = "mpris")), allow(unused_variables))]
             let (
                 worker_task_tx,
                 worker_result_rx,
                 state_rx,
                 state_rx_dbus,
-                control_tx
-            ) = SpotifyWorker::start();
+                position_rx,
+                control_tx,
+                worker_shutdown
+            ) = SpotifyWorker::start(app.v.cache_path.clone(), repaint.clone());
 
-            #[cfg(target_os = "linux")]
-            #[cfg(not(debug_assertions))]
-            crate::dbus::start_dbus_server(state_rx_dbus, control_tx.clone());
+            #[cfg_attr(not(all(target_os = "linux", feature = "mpris")), allow(unused_variables))]
+            let (app_command_tx, app_command_rx) = mpsc::unbounded_channel();
+
+            #[cfg(all(target_os = "linux", feature = "mpris"))]
+            crate::dbus::start_dbus_server(state_rx_dbus, position_rx.clone(), control_tx.clone(), app_command_tx, repaint, app.v.cache_path.clone());
+
+            app.v.app_command_rx = Some(app_command_rx);
 
             app.v.state_rx = Some(state_rx);
+            app.v.position_rx = Some(position_rx);
             app.v.control_tx = Some(control_tx);
 
             app.v.worker_task_tx = Some(worker_task_tx);
             app.v.worker_result_rx = Some(worker_result_rx);
+            app.v.worker_shutdown = Some(worker_shutdown);
+
+            // The worker holds on to these until a mixer and player are created on login.
+            app.send_player_msg(PlayerControl::SetVolume(app.p.volume));
+            app.send_player_msg(PlayerControl::SetAudioSettings(app.config.audio.clone()));
         }
 
         app.v.playback_status.paused = true;
-        app.p.cache_path = dirs::cache_dir().unwrap().join("espot-rs");
+        app.apply_ui_settings(&cc.egui_ctx);
 
         let mut definitions = egui::FontDefinitions::default();
 
@@ -245,9 +362,11 @@ impl EspotApp {
 
                             self.v.keyring_login_attempted = true;
 
-                            if let Ok(data) = ron::from_str(&entry.get_password().unwrap_or_default()) {
+                            if let Ok(mut data) = ron::from_str::<LoginData>(&entry.get_password().unwrap_or_default()) {
+                                data.oauth = self.config.oauth.clone();
+
                                 self.v.waiting_for_login_result = true;
-                                self.send_worker_msg(WorkerTask::Login(data));
+                                self.send_worker_msg(WorkerTask::Login(Box::new(data)));
                             }
                             else {
                                 println!("oof")
@@ -257,11 +376,12 @@ impl EspotApp {
                             let login_data = LoginData {
                                 username: self.p.login_username.clone(),
                                 password: self.v.login_password.clone(),
-                                api_token: None
+                                api_token: None,
+                                oauth: self.config.oauth.clone()
                             };
     
                             self.v.waiting_for_login_result = true;
-                            self.send_worker_msg(WorkerTask::Login(login_data));
+                            self.send_worker_msg(WorkerTask::Login(Box::new(login_data)));
                         }
                     }
                     else {
@@ -272,6 +392,30 @@ impl EspotApp {
         });
     }
 
+    fn draw_task_errors(&mut self, ctx: &egui::Context) {
+        if self.v.task_errors.is_empty() {
+            return;
+        }
+
+        egui::TopBottomPanel::top("task_errors").show(ctx, | ui | {
+            let mut dismissed = None;
+
+            for (i, (kind, message)) in self.v.task_errors.iter().enumerate() {
+                ui.horizontal(| ui | {
+                    if ui.small_button("✖").clicked() {
+                        dismissed = Some(i);
+                    }
+
+                    ui.colored_label(egui::Color32::LIGHT_RED, format!("{} failed: {}", kind, message));
+                });
+            }
+
+            if let Some(i) = dismissed {
+                self.v.task_errors.remove(i);
+            }
+        });
+    }
+
     fn draw_main_screen(&mut self, ctx: &egui::Context) {
         egui::TopBottomPanel::bottom("playback_status").show(ctx, | ui | {
             self.draw_playback_status(ui);
@@ -286,7 +430,10 @@ impl EspotApp {
                 CurrentPanel::Home => self.draw_home_panel(ui),
                 CurrentPanel::Search { .. } => self.draw_search_panel(ui),
                 CurrentPanel::Playlist { .. } => self.draw_playlist_panel(ui),
-                CurrentPanel::Recommendations { .. } => self.draw_recommendations_panel(ui)
+                CurrentPanel::Recommendations { .. } => self.draw_recommendations_panel(ui),
+                CurrentPanel::Queue { .. } => self.draw_queue_panel(ui),
+                CurrentPanel::RecentlyPlayed { .. } => self.draw_recently_played_panel(ui),
+                CurrentPanel::Settings { .. } => self.draw_settings_panel(ui)
             }
         });
     }
@@ -336,6 +483,7 @@ impl EspotApp {
                                         CurrentPanel::Search { tracks_info, .. } => tracks_info.clone(),
                                         CurrentPanel::Playlist { tracks_info, .. } => tracks_info.clone(),
                                         CurrentPanel::Recommendations { tracks_info, .. } => tracks_info.clone(),
+                                        CurrentPanel::RecentlyPlayed { tracks_info, .. } => tracks_info.clone(),
                                         _ => return
                                     }
                                 };
@@ -359,9 +507,90 @@ impl EspotApp {
                             self.send_player_msg(PlayerControl::NextTrack);
                         }
                     });
-                })
+
+                    ui.separator();
+
+                    let shuffle = self.v.playback_status.shuffle;
+
+                    if ui.selectable_label(shuffle, "🔀").on_hover_text("Shuffle").clicked() {
+                        self.send_player_msg(PlayerControl::SetShuffle(!shuffle));
+                    }
+
+                    let (repeat_label, repeat_hover, next_repeat) = match self.v.playback_status.repeat {
+                        RepeatMode::None => ("🔁", "Repeat: off", RepeatMode::Playlist),
+                        RepeatMode::Playlist => ("🔁", "Repeat: playlist", RepeatMode::Track),
+                        RepeatMode::Track => ("🔂", "Repeat: track", RepeatMode::None)
+                    };
+
+                    let repeat_enabled = self.v.playback_status.repeat != RepeatMode::None;
+
+                    if ui.selectable_label(repeat_enabled, repeat_label).on_hover_text(repeat_hover).clicked() {
+                        self.send_player_msg(PlayerControl::SetRepeat(next_repeat));
+                    }
+
+                    ui.separator();
+                    self.draw_volume_control(ui);
+
+                    if let Some(bitrate) = self.v.playback_status.bitrate {
+                        ui.separator();
+
+                        let label = ui.label(bitrate.to_string());
+
+                        if self.v.data_saver {
+                            label.on_hover_text("Data saver is on");
+                        }
+                    }
+                });
+
+                self.draw_progress_bar(ui);
+            });
+        });
+    }
+
+    fn draw_progress_bar(&mut self, ui: &mut egui::Ui) {
+        let mut seek_target = None;
+
+        ui.horizontal(| ui | {
+            let status = &mut self.v.playback_status;
+            let duration_ms = status.current_track.as_ref().map(| t | t.duration_ms as u32).unwrap_or_default();
+            let mut position_ms = status.seek_preview_ms.unwrap_or_else(|| status.position_ms());
+
+            ui.label(utils::format_duration(position_ms as u128));
+
+            ui.add_enabled_ui(status.current_track.is_some(), | ui | {
+                ui.spacing_mut().slider_width = 300.0;
+
+                let slider = egui::Slider::new(&mut position_ms, 0..=duration_ms).show_value(false);
+                let response = ui.add(slider);
+
+                if response.dragged() {
+                    status.seek_preview_ms = Some(position_ms);
+                }
+                else if response.drag_released() || response.changed() {
+                    status.seek_preview_ms = None;
+                    seek_target = Some(position_ms);
+                }
             });
+
+            ui.label(utils::format_duration(duration_ms as u128));
         });
+
+        if let Some(position_ms) = seek_target {
+            self.send_player_msg(PlayerControl::Seek(position_ms));
+        }
+    }
+
+    fn draw_volume_control(&mut self, ui: &mut egui::Ui) {
+        let mut percent = (self.p.volume as u32 * 100 / u16::MAX as u32) as u8;
+
+        ui.label("🔊");
+
+        let slider = egui::Slider::new(&mut percent, 0..=100).suffix("%");
+
+        if ui.add(slider).changed() {
+            self.p.volume = (percent as u32 * u16::MAX as u32 / 100) as u16;
+            self.send_player_msg(PlayerControl::SetVolume(self.p.volume));
+        }
     }
 
     fn draw_side_panel(&mut self, ui: &mut egui::Ui) {
@@ -370,6 +599,7 @@ impl EspotApp {
             ui.separator();
 
             if ui.selectable_label(self.v.current_panel == CurrentPanel::Home, "Home").clicked() {
+                self.cancel_panel_request();
                 self.v.current_panel = CurrentPanel::Home;
             }
 
@@ -379,16 +609,49 @@ impl EspotApp {
                 let checked = matches!(self.v.current_panel, CurrentPanel::Search { .. });
 
                 if ui.selectable_label(checked, "Search").clicked() {
+                    self.cancel_panel_request();
                     self.v.current_panel = CurrentPanel::Search {
                         query: String::new(),
                         search_type: SearchType::Track,
                         result: None,
                         tracks_info: Vec::new(),
-                        waiting_for_info: false
+                        waiting_for_info: false,
+                        request: None
                     };
                 }
             }
 
+            {
+                let checked = matches!(self.v.current_panel, CurrentPanel::Queue { .. });
+
+                if ui.selectable_label(checked, "Queue").clicked() {
+                    self.cancel_panel_request();
+                    self.v.current_panel = CurrentPanel::Queue { dragged: None };
+                }
+            }
+
+            {
+                let checked = matches!(self.v.current_panel, CurrentPanel::RecentlyPlayed { .. });
+
+                if ui.selectable_label(checked, "Recently played").clicked() {
+                    self.cancel_panel_request();
+                    self.v.current_panel = CurrentPanel::RecentlyPlayed {
+                        tracks_info: Vec::new(),
+                        waiting_for_info: true,
+                        request: self.send_worker_msg(WorkerTask::GetRecentlyPlayed)
+                    };
+                }
+            }
+
+            {
+                let checked = matches!(self.v.current_panel, CurrentPanel::Settings { .. });
+
+                if ui.selectable_label(checked, "Settings").clicked() {
+                    self.cancel_panel_request();
+                    self.v.current_panel = CurrentPanel::Settings { config: self.config.clone() };
+                }
+            }
+
             ui.separator();
 
             let playlists = ui.collapsing("Playlists", | ui | {
@@ -430,22 +693,22 @@ impl EspotApp {
                         });
 
                         if label_clicked || opened_from_ctx_menu {
+                            self.cancel_panel_request();
                             self.v.current_panel = CurrentPanel::Playlist {
                                 id: _id.clone(),
                                 data: p.clone(),
                                 tracks_info: Vec::new(),
-                                waiting_for_info: true
+                                waiting_for_info: true,
+                                request: self.send_worker_msg(WorkerTask::GetPlaylistTracksInfo(p.clone()))
                             };
-
-                            self.send_worker_msg(WorkerTask::GetPlaylistTracksInfo(p.clone()));
                         }
                         else if get_recommendations {
+                            self.cancel_panel_request();
                             self.v.current_panel = CurrentPanel::Recommendations {
                                 tracks_info: Vec::new(),
-                                waiting_for_info: true
+                                waiting_for_info: true,
+                                request: self.send_worker_msg(WorkerTask::GetRecommendationsForPlaylist(p.clone()))
                             };
-                            
-                            self.send_worker_msg(WorkerTask::GetRecommendationsForPlaylist(p.clone()));
                         }
                     }
                 }
@@ -456,6 +719,7 @@ impl EspotApp {
 
             playlists.header_response.context_menu(| ui | {
                 if ui.selectable_label(false, "Refresh").clicked() {
+                    self.cancel_panel_request();
                     self.v.current_panel = CurrentPanel::Home;
 
                     self.v.user_playlists = Vec::new();
@@ -470,7 +734,7 @@ impl EspotApp {
             ui.separator();
 
             let (selected, empty,  waiting) = match &self.v.current_panel {
-                CurrentPanel::Recommendations { tracks_info, waiting_for_info } => (true, tracks_info.is_empty(), *waiting_for_info),
+                CurrentPanel::Recommendations { tracks_info, waiting_for_info, .. } => (true, tracks_info.is_empty(), *waiting_for_info),
                 _ => (false, false, true)
             };
 
@@ -510,14 +774,14 @@ impl EspotApp {
                     let label = ui.put(button.rect, egui::Label::new(text));
 
                     if button.clicked() || label.clicked() {
+                        self.cancel_panel_request();
                         self.v.current_panel = CurrentPanel::Playlist {
                             id: id.clone(),
                             data: playlist.clone(),
                             tracks_info: Vec::new(),
-                            waiting_for_info: true
+                            waiting_for_info: true,
+                            request: self.send_worker_msg(WorkerTask::GetPlaylistTracksInfo(playlist.clone()))
                         };
-
-                        self.send_worker_msg(WorkerTask::GetPlaylistTracksInfo(playlist.clone()));
                     }
                 }
             });
@@ -608,8 +872,14 @@ impl EspotApp {
             };
 
             if submitted {
+                self.cancel_panel_request();
+
                 if let CurrentPanel::Search { query, search_type, .. } = &self.v.current_panel {
-                    self.send_worker_msg(WorkerTask::Search(query.clone(), *search_type));
+                    let id = self.send_worker_msg(WorkerTask::Search(query.clone(), *search_type));
+
+                    if let CurrentPanel::Search { request, .. } = &mut self.v.current_panel {
+                        *request = Some(id);
+                    }
                 }
             }
         });
@@ -667,7 +937,7 @@ impl EspotApp {
 
     fn draw_recommendations_panel(&mut self, ui: &mut egui::Ui) {
         ui.horizontal(| ui | {
-            if let CurrentPanel::Recommendations { tracks_info, waiting_for_info } = &self.v.current_panel {
+            if let CurrentPanel::Recommendations { tracks_info, waiting_for_info, .. } = &self.v.current_panel {
                 if !waiting_for_info {
                     let tracks = tracks_info.len();
     
@@ -698,6 +968,401 @@ impl EspotApp {
         self.draw_songs_list(ui);
     }
 
+    fn draw_recently_played_panel(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(| ui | {
+            if let CurrentPanel::RecentlyPlayed { tracks_info, waiting_for_info, .. } = &self.v.current_panel {
+                if !waiting_for_info {
+                    let label = {
+                        if tracks_info.len() == 1 {
+                            String::from("Recently played (1 track)")
+                        }
+                        else {
+                            format!("Recently played ({} tracks)", tracks_info.len())
+                        }
+                    };
+
+                    ui.strong(label);
+                }
+                else {
+                    ui.strong("Fetching recently played tracks...");
+                    ui.add(egui::Spinner::new());
+                }
+
+                if ui.add_enabled(!tracks_info.is_empty(), egui::Button::new("Play")).clicked() {
+                    self.v.playback_status.started = true;
+                    self.send_player_msg(PlayerControl::StartPlaylist(tracks_info.clone()));
+                }
+            }
+        });
+
+        ui.separator();
+        self.draw_songs_list(ui);
+    }
+
+    fn draw_settings_panel(&mut self, ui: &mut egui::Ui) {
+        let draft = match &self.v.current_panel {
+            CurrentPanel::Settings { config } => config.clone(),
+            _ => return
+        };
+
+        ui.horizontal(| ui | {
+            ui.strong("Settings");
+
+            if ui.add_enabled(draft != self.config, egui::Button::new("Apply")).clicked() {
+                self.apply_settings(ui.ctx(), draft.clone());
+            }
+
+            ui.separator();
+
+            let data_saver = egui::Checkbox::new(&mut self.v.data_saver, "Data saver");
+            let data_saver = ui.add(data_saver).on_hover_text("Streams at 96 kbps until espot-rs is closed.");
+
+            if data_saver.changed() {
+                self.send_player_msg(PlayerControl::SetDataSaver(self.v.data_saver));
+            }
+        });
+
+        ui.separator();
+
+        let restart_needed = draft.cache_path != self.v.cache_path;
+
+        let config = match &mut self.v.current_panel {
+            CurrentPanel::Settings { config } => config,
+            _ => return
+        };
+
+        egui::ScrollArea::vertical().show(ui, | ui | {
+            ui.strong("General");
+            ui.separator();
+
+            egui::Grid::new("general_settings").num_columns(2).spacing([20.0, 5.0]).show(ui, | ui | {
+                ui.label("Cache folder");
+
+                let mut cache_path = config.cache_path.display().to_string();
+
+                if ui.text_edit_singleline(&mut cache_path).changed() {
+                    config.cache_path = PathBuf::from(cache_path);
+                }
+
+                ui.end_row();
+            });
+
+            if restart_needed {
+                ui.label("The new cache folder is used once espot-rs is restarted.");
+            }
+
+            ui.add_space(10.0);
+            ui.strong("Audio");
+            ui.separator();
+
+            let audio = &mut config.audio;
+
+            egui::Grid::new("audio_settings").num_columns(2).spacing([20.0, 5.0]).show(ui, | ui | {
+                ui.label("Backend");
+
+                egui::ComboBox::from_id_source("audio_backend")
+                    .selected_text(audio.backend.as_deref().unwrap_or("Default"))
+                    .show_ui(ui, | ui | {
+                        ui.selectable_value(&mut audio.backend, None, "Default");
+
+                        for backend in audio_backends() {
+                            ui.selectable_value(&mut audio.backend, Some(backend.to_string()), backend);
+                        }
+                    })
+                ;
+
+                ui.end_row();
+
+                ui.label("Device");
+                utils::optional_text_edit(ui, &mut audio.device, "Default", false);
+                ui.end_row();
+
+                ui.label("Quality");
+
+                egui::ComboBox::from_id_source("audio_bitrate")
+                    .selected_text(audio.bitrate.to_string())
+                    .show_ui(ui, | ui | {
+                        for bitrate in Bitrate::ALL {
+                            ui.selectable_value(&mut audio.bitrate, bitrate, bitrate.to_string());
+                        }
+                    })
+                ;
+
+                ui.end_row();
+
+                ui.label("Sample format");
+
+                egui::ComboBox::from_id_source("audio_format")
+                    .selected_text(audio.format.to_string())
+                    .show_ui(ui, | ui | {
+                        for format in SampleFormat::ALL {
+                            ui.selectable_value(&mut audio.format, format, format.to_string());
+                        }
+                    })
+                ;
+
+                ui.end_row();
+            });
+
+            if audio.backend.as_deref() == Some("pipe") {
+                ui.label("The pipe backend writes raw samples to the file set as the device, or to stdout without one.");
+            }
+
+            ui.add_space(10.0);
+            ui.strong("Playback");
+            ui.separator();
+
+            ui.checkbox(&mut audio.gapless, "Gapless playback");
+
+            let normalisation = &mut audio.normalisation;
+            ui.checkbox(&mut normalisation.enabled, "Normalise volume");
+
+            ui.add_enabled_ui(normalisation.enabled, | ui | {
+                egui::Grid::new("normalisation_settings").num_columns(2).spacing([20.0, 5.0]).show(ui, | ui | {
+                    ui.label("Type");
+
+                    egui::ComboBox::from_id_source("normalisation_type")
+                        .selected_text(normalisation.kind.to_string())
+                        .show_ui(ui, | ui | {
+                            for kind in NormalisationType::ALL {
+                                ui.selectable_value(&mut normalisation.kind, kind, kind.to_string());
+                            }
+                        })
+                    ;
+
+                    ui.end_row();
+
+                    ui.label("Method");
+
+                    egui::ComboBox::from_id_source("normalisation_method")
+                        .selected_text(normalisation.method.to_string())
+                        .show_ui(ui, | ui | {
+                            for method in NormalisationMethod::ALL {
+                                ui.selectable_value(&mut normalisation.method, method, method.to_string());
+                            }
+                        })
+                    ;
+
+                    ui.end_row();
+
+                    ui.label("Pregain");
+                    ui.add(egui::Slider::new(&mut normalisation.pregain_db, -10.0..=10.0).suffix(" dB"));
+                    ui.end_row();
+
+                    // Only the dynamic method has a limiter.
+                    let dynamic = normalisation.method == NormalisationMethod::Dynamic;
+
+                    ui.label("Threshold");
+                    ui.add_enabled(dynamic, egui::Slider::new(&mut normalisation.threshold_dbfs, -10.0..=0.0).suffix(" dBFS"));
+                    ui.end_row();
+
+                    ui.label("Attack");
+                    ui.add_enabled(dynamic, egui::Slider::new(&mut normalisation.attack_ms, 1..=500).suffix(" ms"));
+                    ui.end_row();
+
+                    ui.label("Release");
+                    ui.add_enabled(dynamic, egui::Slider::new(&mut normalisation.release_ms, 1..=1000).suffix(" ms"));
+                    ui.end_row();
+                });
+            });
+
+            ui.add_space(10.0);
+            ui.strong("Spotify API");
+            ui.separator();
+
+            let oauth = &mut config.oauth;
+
+            egui::Grid::new("oauth_settings").num_columns(2).spacing([20.0, 5.0]).show(ui, | ui | {
+                ui.label("Client ID");
+                utils::optional_text_edit(ui, &mut oauth.client_id, "From .env", false);
+                ui.end_row();
+
+                ui.label("Client secret");
+                utils::optional_text_edit(ui, &mut oauth.client_secret, "None, uses PKCE", true);
+                ui.end_row();
+
+                ui.label("Redirect URI");
+                utils::optional_text_edit(ui, &mut oauth.redirect_uri, DEFAULT_REDIRECT_URI, false);
+                ui.end_row();
+            });
+
+            ui.label("These are used the next time you log in. Without a client ID, they're read from the .env file.");
+
+            ui.add_space(10.0);
+            ui.strong("Interface");
+            ui.separator();
+
+            let ui_settings = &mut config.ui;
+
+            egui::Grid::new("ui_settings").num_columns(2).spacing([20.0, 5.0]).show(ui, | ui | {
+                ui.label("Theme");
+
+                egui::ComboBox::from_id_source("ui_theme")
+                    .selected_text(ui_settings.theme.to_string())
+                    .show_ui(ui, | ui | {
+                        for theme in Theme::ALL {
+                            ui.selectable_value(&mut ui_settings.theme, theme, theme.to_string());
+                        }
+                    })
+                ;
+
+                ui.end_row();
+
+                ui.label("Scale");
+                ui.add(egui::Slider::new(&mut ui_settings.scale, 0.5..=2.0).fixed_decimals(2));
+                ui.end_row();
+            });
+        });
+    }
+
+    fn apply_settings(&mut self, ctx: &egui::Context, config: Config) {
+        if config.audio != self.config.audio {
+            self.send_player_msg(PlayerControl::SetAudioSettings(config.audio.clone()));
+        }
+
+        self.config = config;
+        self.apply_ui_settings(ctx);
+
+        if let Err(e) = self.config.save() {
+            println!("Error saving config: {}", e);
+        }
+    }
+
+    fn apply_ui_settings(&self, ctx: &egui::Context) {
+        let dark_mode = match self.config.ui.theme {
+            Theme::System => self.v.system_dark_mode.unwrap_or(true),
+            Theme::Dark => true,
+            Theme::Light => false
+        };
+
+        ctx.set_visuals(if dark_mode {egui::Visuals::dark()} else {egui::Visuals::light()});
+        ctx.set_pixels_per_point(self.v.native_pixels_per_point * self.config.ui.scale);
+    }
+
+    fn draw_queue_panel(&mut self, ui: &mut egui::Ui) {
+        let status = &self.v.playback_status;
+
+        // Only what comes after the current track is up next.
+        let first_upcoming = if status.queue.is_empty() { 0 } else { status.queue_current + 1 };
+
+        let upcoming = status.queue.len().saturating_sub(first_upcoming);
+
+        ui.horizontal(| ui | {
+            let label = {
+                if upcoming == 1 {
+                    String::from("Up next (1 track)")
+                }
+                else {
+                    format!("Up next ({} tracks)", upcoming)
+                }
+            };
+
+            ui.strong(label);
+
+            if ui.add_enabled(upcoming > 0, egui::Button::new("Clear")).clicked() {
+                self.send_player_msg(PlayerControl::ClearQueue);
+            }
+        });
+
+        ui.separator();
+
+        let dragged = match &self.v.current_panel {
+            CurrentPanel::Queue { dragged } => *dragged,
+            _ => None
+        };
+
+        let mut drag_started = None;
+        let mut drop_target = None;
+        let mut go_to_track = None;
+        let mut remove_track = None;
+
+        egui::ScrollArea::vertical().show(ui, | ui | {
+            ui.style_mut().wrap = Some(false);
+
+            if upcoming == 0 {
+                ui.label("Nothing's queued up...");
+            }
+
+            let glyph_width = ui.fonts().glyph_width(&egui::TextStyle::Body.resolve(ui.style()), 'の');
+            let pointer_pos = ui.input().pointer.interact_pos();
+
+            for (idx, QueueEntry { track, .. }) in self.v.playback_status.queue.iter().enumerate().skip(first_upcoming) {
+                let row = ui.horizontal(| ui | {
+                    let handle = ui.add(egui::Label::new("☰").sense(egui::Sense::drag()))
+                        .on_hover_cursor(egui::CursorIcon::Grab)
+                    ;
+
+                    if handle.drag_started() {
+                        drag_started = Some(idx);
+                    }
+
+                    let label = format!("{} - {}", track.name, utils::make_artists_string(&track.artists));
+                    let mut trimmed_label = label.clone();
+                    let trimmed = utils::trim_string(ui.available_width() - 64.0, glyph_width, &mut trimmed_label);
+
+                    let mut track_label = ui.selectable_label(dragged == Some(idx), trimmed_label);
+
+                    if trimmed {
+                        track_label = track_label.on_hover_text(label);
+                    }
+
+                    if track_label.clicked() {
+                        go_to_track = Some(idx);
+                    }
+
+                    track_label.context_menu(| ui | {
+                        if ui.selectable_label(false, "Play now").clicked() {
+                            go_to_track = Some(idx);
+                            ui.close_menu();
+                        }
+
+                        if ui.selectable_label(false, "Remove from queue").clicked() {
+                            remove_track = Some(idx);
+                            ui.close_menu();
+                        }
+                    });
+
+                    ui.with_layout(egui::Layout::right_to_left(), | ui | {
+                        ui.label(utils::format_duration(track.duration_ms));
+                    });
+                });
+
+                let rect = row.response.rect;
+
+                if let (Some(from), Some(pos)) = (dragged, pointer_pos) {
+                    if from != idx && rect.contains(pos) {
+                        // Show where the track will end up.
+                        let y = if idx < from { rect.top() } else { rect.bottom() };
+                        ui.painter().hline(rect.x_range(), y, ui.visuals().selection.stroke);
+
+                        drop_target = Some(idx);
+                    }
+                }
+            }
+
+            ui.style_mut().wrap = None;
+        });
+
+        if let Some(idx) = go_to_track {
+            self.send_player_msg(PlayerControl::GoToTrack(idx));
+        }
+
+        if let Some(idx) = remove_track {
+            self.send_player_msg(PlayerControl::RemoveTrack(idx));
+        }
+
+        if let CurrentPanel::Queue { dragged } = &mut self.v.current_panel {
+            if drag_started.is_some() {
+                *dragged = drag_started;
+            }
+            else if dragged.is_some() && ui.input().pointer.any_released() {
+                if let (Some(from), Some(to)) = (dragged.take(), drop_target) {
+                    self.send_player_msg(PlayerControl::MoveTrack(from, to));
+                }
+            }
+        }
+    }
+
     fn draw_songs_list(&mut self, ui: &mut egui::Ui) {
         egui::ScrollArea::vertical().show(ui, | ui | {
             ui.style_mut().wrap = Some(false);
@@ -719,6 +1384,9 @@ impl EspotApp {
                         CurrentPanel::Search { tracks_info, .. } => {
                             tracks_info.iter()
                         }
+                        CurrentPanel::RecentlyPlayed { tracks_info, .. } => {
+                            tracks_info.iter()
+                        }
                         _ => return
                     }
                 };
@@ -781,7 +1449,7 @@ impl EspotApp {
                     };
 
                     let _track_duration_label = {
-                        let duration = format!("{}:{:02}", (track.duration_ms / 1000) / 60, (track.duration_ms / 1000) % 60);
+                        let duration = utils::format_duration(track.duration_ms);
                         let mut duration_string = duration.clone();
                         let trimmed = utils::trim_string(available_width_c3, glyph_width, &mut duration_string);
 
@@ -805,6 +1473,9 @@ impl EspotApp {
                                 CurrentPanel::Recommendations { tracks_info, .. } => {
                                     tracks_info.clone()
                                 }
+                                CurrentPanel::RecentlyPlayed { tracks_info, .. } => {
+                                    tracks_info.clone()
+                                }
                                 _ => {
                                     return;
                                 }
@@ -830,6 +1501,9 @@ impl EspotApp {
                                     CurrentPanel::Recommendations { tracks_info, .. } => {
                                         tracks_info.clone()
                                     }
+                                    CurrentPanel::RecentlyPlayed { tracks_info, .. } => {
+                                        tracks_info.clone()
+                                    }
                                     _ => {
                                         return;
                                     }
@@ -840,6 +1514,16 @@ impl EspotApp {
                             ui.close_menu();
                         }
 
+                        if ui.selectable_label(false, "Play next").clicked() {
+                            self.send_player_msg(PlayerControl::PlayNext(track.clone()));
+                            ui.close_menu();
+                        }
+
+                        if ui.selectable_label(false, "Add to queue").clicked() {
+                            self.send_player_msg(PlayerControl::AddToQueue(track.clone()));
+                            ui.close_menu();
+                        }
+
                         ui.menu_button("Add to playlist", | ui | {
                             for (id, playlist) in self.v.user_playlists.iter() {
                                 if ui.selectable_label(false, playlist.name.as_str()).clicked() {
@@ -891,111 +1575,223 @@ impl EspotApp {
     }
 
     fn handle_messages(&mut self) {
-        if let Some(rx) = self.v.state_rx.as_mut() {
-            if let Ok(state) = rx.try_recv() {
-                match state {
-                    PlayerStateUpdate::Paused => {
-                        self.v.playback_status.paused = true;
-                    }
-                    PlayerStateUpdate::Resumed => {
-                        self.v.playback_status.paused = false;
-                    }
-                    PlayerStateUpdate::Stopped => {
-                        self.v.playback_status.current_track = None;
-                        self.v.texture_album_cover = None;
-                    }
-                    PlayerStateUpdate::EndOfTrack(track) => {
-                        self.v.playback_status.paused = false;
-                        self.v.playback_status.current_track = Some(track);
-                        self.v.texture_album_cover = None;
-                    }
+        while let Some(state) = self.next_state_update() {
+            match state {
+                PlayerStateUpdate::Paused => {
+                    self.v.playback_status.paused = true;
+                }
+                PlayerStateUpdate::Resumed => {
+                    self.v.playback_status.paused = false;
+                }
+                PlayerStateUpdate::Stopped => {
+                    self.v.playback_status.paused = true;
+                    self.v.playback_status.started = false;
+                    self.v.playback_status.current_track = None;
+                    self.v.playback_status.position = None;
+                    self.v.texture_album_cover = None;
+                }
+                // The position update that follows a seek is enough for the UI.
+                PlayerStateUpdate::Seeked(_) => {}
+                PlayerStateUpdate::Volume(volume) => {
+                    self.p.volume = volume;
+                }
+                PlayerStateUpdate::Bitrate(bitrate) => {
+                    self.v.playback_status.bitrate = Some(bitrate);
+                }
+                PlayerStateUpdate::Shuffle(shuffle) => {
+                    self.v.playback_status.shuffle = shuffle;
+                }
+                PlayerStateUpdate::Repeat(repeat) => {
+                    self.v.playback_status.repeat = repeat;
+                }
+                PlayerStateUpdate::Queue(entries, current) => {
+                    self.v.playback_status.queue = entries;
+                    self.v.playback_status.queue_current = current;
+                }
+                PlayerStateUpdate::UserPlaylists(_) => {}
+                PlayerStateUpdate::ActivePlaylist(_) => {}
+                PlayerStateUpdate::EndOfTrack(track) => {
+                    // Playback can be started from outside the UI, through the queue or MPRIS.
+                    self.v.playback_status.paused = false;
+                    self.v.playback_status.started = true;
+                    self.v.playback_status.current_track = Some(track);
+                    self.v.texture_album_cover = None;
                 }
             }
         }
 
-        if let Some(rx) = self.v.worker_result_rx.as_mut() {
-            if let Ok(worker_res) = rx.try_recv() {
-                match worker_res {
-                    WorkerResult::Login(result) => {
-                        if let Some(t) = result {
-                            if self.p.login_remember {
-                                let entry = keyring::Entry::new("espot-rs", &self.p.login_username);
-                                let login_data = LoginData {
-                                    username: self.p.login_username.clone(),
-                                    password: self.v.login_password.clone(),
-                                    api_token: Some(t)
-                                };
+        // Only the latest position is kept, there's no falling behind on these.
+        if let Some(rx) = self.v.position_rx.as_mut() {
+            if rx.has_changed().unwrap_or(false) {
+                self.v.playback_status.position = rx.borrow_and_update().clone();
+            }
+        }
 
-                                let serialized = ron::to_string(&login_data).unwrap_or_default();
-                                entry.set_password(&serialized);
-                            }
+        while let Some((id, worker_res)) = self.v.worker_result_rx.as_mut().and_then(| rx | rx.try_recv().ok()) {
+            // Whatever a panel asked for is only wanted while that panel is still open.
+            if is_panel_result(&worker_res) && id != self.v.current_panel.request() {
+                continue;
+            }
+
+            match worker_res {
+                WorkerResult::Login(t) => {
+                    if self.p.login_remember {
+                        let entry = keyring::Entry::new("espot-rs", &self.p.login_username);
+                        let login_data = LoginData {
+                            username: self.p.login_username.clone(),
+                            password: self.v.login_password.clone(),
+                            api_token: Some(t),
+                            oauth: self.config.oauth.clone()
+                        };
+
+                        let serialized = ron::to_string(&login_data).unwrap_or_default();
 
-                            self.v.logged_in = true;
+                        if let Err(e) = entry.set_password(&serialized) {
+                            println!("Error saving login data to keyring: {}", e);
                         }
-    
-                        self.v.login_password = String::new();
-                        self.v.waiting_for_login_result = false;
                     }
-                    WorkerResult::UserPlaylists(playlists) => {
-                        self.v.user_playlists = playlists;
-                        self.v.fetching_user_playlists = false;
-                        self.v.textures_user_playlists_covers = vec![None; self.v.user_playlists.len()];
-                    }
-                    WorkerResult::FeaturedPlaylists(playlists) => {
-                        self.v.featured_playlists = playlists;
-                        self.v.fetching_featured_playlists = false;
-                        self.v.textures_featured_playlists_covers = vec![None; self.v.featured_playlists.len()];
-                    }
-                    WorkerResult::SearchResult(s_result) => {
-                        if let CurrentPanel::Search { result, tracks_info, waiting_for_info, .. } = &mut self.v.current_panel {
-                            if let SearchResult::Tracks(tracks) = &s_result {
-                                *tracks_info = tracks.items
-                                    .iter()
-                                    .filter_map(| t | TrackInfo::new(t.clone()))
-                                    .collect()
-                                ;
-                            }
-    
-                            *result = Some(s_result);
-                            *waiting_for_info = false;
+
+                    self.v.logged_in = true;
+                    self.v.login_password = String::new();
+                    self.v.waiting_for_login_result = false;
+                }
+                WorkerResult::UserPlaylists(playlists) => {
+                    self.v.user_playlists = playlists;
+                    self.v.fetching_user_playlists = false;
+                    self.v.textures_user_playlists_covers = vec![None; self.v.user_playlists.len()];
+                }
+                WorkerResult::FeaturedPlaylists(playlists) => {
+                    self.v.featured_playlists = playlists;
+                    self.v.fetching_featured_playlists = false;
+                    self.v.textures_featured_playlists_covers = vec![None; self.v.featured_playlists.len()];
+                }
+                WorkerResult::SearchResult(s_result) => {
+                    if let CurrentPanel::Search { result, tracks_info, waiting_for_info, .. } = &mut self.v.current_panel {
+                        if let SearchResult::Tracks(tracks) = &s_result {
+                            *tracks_info = tracks.items
+                                .iter()
+                                .filter_map(| t | TrackInfo::new(t.clone()))
+                                .collect()
+                            ;
                         }
-                        
+
+                        *result = Some(s_result);
+                        *waiting_for_info = false;
                     }
-                    WorkerResult::PlaylistTrackInfo(tracks) => {
-                        if let CurrentPanel::Playlist { tracks_info, waiting_for_info, .. } = &mut self.v.current_panel {
-                            *tracks_info = tracks;
-                            *waiting_for_info = false;
-                        }
+                    
+                }
+                WorkerResult::PlaylistTrackInfo(tracks) => {
+                    if let CurrentPanel::Playlist { tracks_info, waiting_for_info, .. } = &mut self.v.current_panel {
+                        *tracks_info = tracks;
+                        *waiting_for_info = false;
                     }
-                    WorkerResult::PlaylistRecommendations(tracks) => {
-                        if let CurrentPanel::Recommendations { tracks_info, waiting_for_info } = &mut self.v.current_panel {
-                            *tracks_info = tracks;
-                            *waiting_for_info = false;
-                        }
+                }
+                WorkerResult::PlaylistRecommendations(tracks) => {
+                    if let CurrentPanel::Recommendations { tracks_info, waiting_for_info, .. } = &mut self.v.current_panel {
+                        *tracks_info = tracks;
+                        *waiting_for_info = false;
                     }
                 }
+                WorkerResult::RecentlyPlayed(tracks) => {
+                    if let CurrentPanel::RecentlyPlayed { tracks_info, waiting_for_info, .. } = &mut self.v.current_panel {
+                        *tracks_info = tracks;
+                        *waiting_for_info = false;
+                    }
+                }
+                WorkerResult::Error(kind, error) => {
+                    self.handle_task_error(kind);
+                    self.v.task_errors.push((kind, error.to_string()));
+                }
             }
         }
     }
 
+    fn next_state_update(&mut self) -> Option<PlayerStateUpdate> {
+        let rx = self.v.state_rx.as_mut()?;
+
+        loop {
+            match rx.try_recv() {
+                Ok(state) => return Some(state),
+                // The skipped updates are gone, but the ones after them are still worth handling.
+                Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                Err(_) => return None
+            }
+        }
+    }
+
+    fn handle_app_commands(&mut self, frame: &mut eframe::Frame) {
+        while let Some(command) = self.v.app_command_rx.as_mut().and_then(| rx | rx.try_recv().ok()) {
+            match command {
+                AppCommand::Quit => frame.quit()
+            }
+        }
+    }
+
+    // Clears whatever "waiting" state the failed task left behind.
+    fn handle_task_error(&mut self, kind: TaskKind) {
+        match kind {
+            TaskKind::Login => {
+                self.v.login_password = String::new();
+                self.v.waiting_for_login_result = false;
+            }
+            TaskKind::GetUserPlaylists => {
+                self.v.fetching_user_playlists = false;
+            }
+            TaskKind::GetFeaturedPlaylists => {
+                self.v.fetching_featured_playlists = false;
+            }
+            TaskKind::StartPlaylist => {
+                self.v.playback_status.paused = true;
+                self.v.playback_status.started = false;
+            }
+            _ => {
+                match &mut self.v.current_panel {
+                    CurrentPanel::Search { waiting_for_info, .. } => *waiting_for_info = false,
+                    CurrentPanel::Playlist { waiting_for_info, .. } => *waiting_for_info = false,
+                    CurrentPanel::Recommendations { waiting_for_info, .. } => *waiting_for_info = false,
+                    CurrentPanel::RecentlyPlayed { waiting_for_info, .. } => *waiting_for_info = false,
+                    CurrentPanel::Home | CurrentPanel::Queue { .. } | CurrentPanel::Settings { .. } => {}
+                }
+            }
+        }
+    }
+
+    fn has_task_error(&self, kind: TaskKind) -> bool {
+        self.v.task_errors.iter().any(| (k, _) | *k == kind)
+    }
+
     fn is_playlist_ready(&self) -> bool {
         match &self.v.current_panel {
-            CurrentPanel::Home => self.v.playback_status.started,
+            CurrentPanel::Home | CurrentPanel::Queue { .. } | CurrentPanel::Settings { .. } => self.v.playback_status.started,
             CurrentPanel::Search { result, tracks_info, waiting_for_info, .. } => {
                 result.is_some() && !tracks_info.is_empty() && !waiting_for_info
             }
             CurrentPanel::Playlist { data, tracks_info, waiting_for_info, .. } => {
                 data.tracks.len() == tracks_info.len() && !waiting_for_info
             }
-            CurrentPanel::Recommendations { tracks_info, waiting_for_info } => {
+            CurrentPanel::Recommendations { tracks_info, waiting_for_info, .. } => {
+                !tracks_info.is_empty() && !waiting_for_info
+            }
+            CurrentPanel::RecentlyPlayed { tracks_info, waiting_for_info, .. } => {
                 !tracks_info.is_empty() && !waiting_for_info
             }
         }
     }
 
-    fn send_worker_msg(&self, message: WorkerTask) {
+    fn send_worker_msg(&self, message: WorkerTask) -> RequestId {
+        let id = RequestId::next();
+
         if let Some(tx) = self.v.worker_task_tx.as_ref() {
-            tx.send(message).unwrap();
+            tx.send(WorkerRequest::Task(id, message)).unwrap();
+        }
+
+        id
+    }
+
+    // The worker doesn't need to keep loading things for a panel that's being replaced.
+    fn cancel_panel_request(&self) {
+        if let (Some(tx), Some(id)) = (self.v.worker_task_tx.as_ref(), self.v.current_panel.request()) {
+            tx.send(WorkerRequest::Cancel(id)).unwrap();
         }
     }
 
@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use eframe::egui::{Context, ColorImage, TextureHandle};
+use eframe::egui::{Context, ColorImage, Response, TextEdit, TextureHandle, Ui};
 
 
 pub fn create_texture_from_file(ctx: &Context, path: PathBuf) -> Option<TextureHandle> {
@@ -50,3 +50,19 @@ pub fn trim_string(available_width: f32, glyph_width: f32, text: &mut String) ->
 
     should_trim
 }
+
+pub fn format_duration(duration_ms: u128) -> String {
+    format!("{}:{:02}", (duration_ms / 1000) / 60, (duration_ms / 1000) % 60)
+}
+
+// Edits a setting that's left unset while empty.
+pub fn optional_text_edit(ui: &mut Ui, value: &mut Option<String>, hint: &str, password: bool) -> Response {
+    let mut text = value.clone().unwrap_or_default();
+    let response = ui.add(TextEdit::singleline(&mut text).hint_text(hint).password(password));
+
+    if response.changed() {
+        *value = if text.is_empty() {None} else {Some(text)};
+    }
+
+    response
+}